@@ -1,20 +1,116 @@
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Local, NaiveTime, TimeDelta, TimeZone};
+use clap::{Args, Parser, Subcommand};
+use nix::unistd::getuid;
 use sima_proto::{
-    FALLBACK_SOCKET_PATH, PRIMARY_SOCKET_PATH, Request, Response, decode, encode,
-    should_fallback_from_connect_error,
+    Enablement, FALLBACK_SOCKET_PATH, KexecImage, PRIMARY_SOCKET_PATH, Request, Response,
+    ScheduledShutdown, ShutdownKind, decode, encode, ensure_private_dir,
+    should_fallback_from_connect_error, user_fallback_dir, user_socket_path,
 };
 use std::io::{Read, Write};
 use std::net::Shutdown;
 use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command as ProcessCommand;
 
 #[derive(Parser)]
 #[command(name = "simactl", about = "SIMA service manager CLI")]
 struct Cli {
+    /// Connect to this socket instead of the default paths
+    #[arg(long, global = true)]
+    socket: Option<String>,
+    /// Connect to the supervisor (`sima-init --supervisor`) of the current user
+    #[arg(long, global = true, conflicts_with = "socket")]
+    user: bool,
     #[command(subcommand)]
     command: Command,
 }
 
+/// When to shut down; right away without either option.
+#[derive(Args)]
+struct When {
+    /// Shut down at this local time, today or else tomorrow
+    #[arg(long, value_name = "HH:MM", conflicts_with = "delay")]
+    at: Option<String>,
+    /// Shut down after this delay, e.g. `5m`, `1h30m` or `90s`; a bare number is minutes
+    #[arg(long = "in", id = "delay", value_name = "DELAY")]
+    delay: Option<String>,
+}
+
+impl When {
+    /// The time to shut down, in seconds since the Unix epoch, if not right away.
+    fn deadline(&self) -> Result<Option<u64>> {
+        let now = Local::now();
+        let deadline = match (&self.at, &self.delay) {
+            (Some(at), _) => parse_time_of_day(at, now)?,
+            (None, Some(delay)) => now + parse_delay(delay)?,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(deadline.timestamp().try_into()?))
+    }
+
+    /// `immediate`, or a request to shut down as `kind` later.
+    fn request(&self, kind: ShutdownKind, immediate: Request) -> Result<Request> {
+        Ok(match self.deadline()? {
+            Some(at) => Request::ScheduleShutdown(ScheduledShutdown { kind, at }),
+            None => immediate,
+        })
+    }
+}
+
+/// The next time it is `at` (`HH:MM`) after `now`, in the time zone of `now`.
+fn parse_time_of_day<Tz: TimeZone>(at: &str, now: DateTime<Tz>) -> Result<DateTime<Tz>> {
+    let time = NaiveTime::parse_from_str(at, "%H:%M")
+        .with_context(|| format!("Invalid time `{at}`: expected HH:MM"))?;
+    let mut date = now.date_naive();
+    loop {
+        // A time skipped by a change to daylight saving time moves to the next day.
+        if let Some(deadline) = date
+            .and_time(time)
+            .and_local_timezone(now.timezone())
+            .earliest()
+            && deadline > now
+        {
+            return Ok(deadline);
+        }
+        date = date
+            .succ_opt()
+            .with_context(|| format!("Invalid time `{at}`"))?;
+    }
+}
+
+/// Parses a delay such as `5m`, `1h30m` or `90s`; a bare number is minutes.
+fn parse_delay(delay: &str) -> Result<TimeDelta> {
+    let invalid = || anyhow!("Invalid delay `{delay}`: expected e.g. 5m, 1h30m or 90s");
+    // Unsigned, so that a negative delay is refused rather than meaning right away.
+    if let Ok(minutes) = delay.parse::<u32>() {
+        return Ok(TimeDelta::minutes(i64::from(minutes)));
+    }
+
+    let mut total = TimeDelta::zero();
+    let mut rest = delay;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value: i64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let unit = rest[digits..].chars().next().ok_or_else(invalid)?;
+        let part = match unit {
+            'h' => TimeDelta::try_hours(value),
+            'm' => TimeDelta::try_minutes(value),
+            's' => TimeDelta::try_seconds(value),
+            _ => None,
+        }
+        .ok_or_else(invalid)?;
+        total = total.checked_add(&part).ok_or_else(invalid)?;
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+    if total.is_zero() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
 #[derive(Subcommand)]
 enum Command {
     /// Start a service
@@ -26,33 +122,155 @@ enum Command {
     /// Show status of all services
     Status,
     /// Power off the system
-    Poweroff,
+    Poweroff {
+        #[command(flatten)]
+        when: When,
+    },
     /// Reboot the system
-    Reboot,
+    Reboot {
+        /// Argument for the firmware or bootloader, e.g. `bootloader` or `recovery`
+        #[arg(long, conflicts_with_all = ["at", "delay"])]
+        arg: Option<String>,
+        #[command(flatten)]
+        when: When,
+    },
     /// Soft-reboot (restart userspace only)
     SoftReboot,
+    /// Halt the system without powering it off
+    Halt {
+        #[command(flatten)]
+        when: When,
+    },
+    /// Show or cancel the scheduled shutdown
+    Shutdown {
+        /// Cancel the scheduled shutdown
+        #[arg(long)]
+        cancel: bool,
+    },
+    /// Reboot into another kernel with kexec
+    Kexec {
+        /// Kernel image to load; without one, the kernel already loaded is used
+        #[arg(long)]
+        kernel: Option<PathBuf>,
+        /// Initial ramdisk for the kernel
+        #[arg(long, requires = "kernel")]
+        initrd: Option<PathBuf>,
+        /// Kernel command line; defaults to the current one
+        #[arg(long, requires = "kernel")]
+        cmdline: Option<String>,
+    },
+    /// Start a service at boot
+    Enable { name: String },
+    /// Do not start a service at boot
+    Disable { name: String },
+    /// Prevent a service from starting, even on request
+    Mask { name: String },
+    /// Undo `mask`
+    Unmask { name: String },
+    /// Start the services of a target and stop all others
+    Isolate { target: String },
+    /// Show the definition of a service with drop-ins applied
+    Cat { name: String },
+    /// Reload service definitions and apply the changes
+    DaemonReload,
+    /// Execute sima-init again, e.g. after an upgrade, keeping the services running
+    DaemonReexec,
+    /// Validate a system manifest and its service files without contacting sima-init
+    Verify {
+        /// Manifest to check [default: /etc/sima.yml]
+        path: Option<PathBuf>,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    if let Command::Verify { path } = &cli.command {
+        return verify(path.as_deref());
+    }
+
+    let shutdown_query = matches!(cli.command, Command::Shutdown { cancel: false });
     let request = match cli.command {
         Command::Start { name } => Request::Start(name),
         Command::Stop { name } => Request::Stop(name),
         Command::Restart { name } => Request::Restart(name),
         Command::Status => Request::Status,
-        Command::Poweroff => Request::Poweroff,
-        Command::Reboot => Request::Reboot,
+        Command::Poweroff { when } => when.request(ShutdownKind::Poweroff, Request::Poweroff)?,
+        Command::Reboot { arg, when } => {
+            when.request(ShutdownKind::Reboot, Request::Reboot { arg })?
+        }
         Command::SoftReboot => Request::SoftReboot,
+        Command::Halt { when } => when.request(ShutdownKind::Halt, Request::Halt)?,
+        Command::Shutdown { cancel: true } => Request::CancelShutdown,
+        Command::Shutdown { cancel: false } => Request::Status,
+        Command::Kexec {
+            kernel,
+            initrd,
+            cmdline,
+        } => Request::Kexec(KexecImage {
+            // sima-init does not run in our working directory.
+            kernel: kernel.map(std::path::absolute).transpose()?,
+            initrd: initrd.map(std::path::absolute).transpose()?,
+            cmdline,
+        }),
+        Command::Enable { name } => Request::Enable(name),
+        Command::Disable { name } => Request::Disable(name),
+        Command::Mask { name } => Request::Mask(name),
+        Command::Unmask { name } => Request::Unmask(name),
+        Command::Isolate { target } => Request::Isolate(target),
+        Command::Cat { name } => Request::Cat(name),
+        Command::DaemonReload => Request::ReloadConfig,
+        Command::DaemonReexec => Request::DaemonReexec,
+        Command::Verify { .. } => unreachable!("handled above"),
     };
 
-    let response = send_request(request)?;
-    print_response(response);
+    let socket = if cli.user {
+        let uid = getuid().as_raw();
+        let socket = user_socket_path(uid);
+        let dir = user_fallback_dir(uid);
+        // Anyone could have created the directory to answer in the supervisor's place.
+        if Path::new(&socket).starts_with(&dir) {
+            ensure_private_dir(&dir, uid)
+                .with_context(|| format!("Refusing to connect through {}", dir.display()))?;
+        }
+        Some(socket)
+    } else {
+        cli.socket
+    };
+    let response = send_request(request, socket.as_deref())?;
+    match response {
+        Response::StatusReport(_, None) if shutdown_query => println!("No shutdown scheduled."),
+        Response::StatusReport(_, Some(shutdown)) if shutdown_query => print_scheduled(&shutdown),
+        response => print_response(response),
+    }
     Ok(())
 }
 
-fn send_request(req: Request) -> Result<Response> {
-    let mut stream = match UnixStream::connect(PRIMARY_SOCKET_PATH) {
+/// Runs `sima-init --check-config`, preferring the binary installed next to simactl.
+fn verify(path: Option<&Path>) -> Result<()> {
+    let sibling = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("sima-init")))
+        .filter(|candidate| candidate.is_file());
+    let mut command = ProcessCommand::new(sibling.as_deref().unwrap_or(Path::new("sima-init")));
+    command.arg("--check-config");
+    if let Some(path) = path {
+        command.arg("--config").arg(path);
+    }
+
+    let status = command
+        .status()
+        .context("Failed to run sima-init --check-config")?;
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+fn connect(socket: Option<&str>) -> Result<UnixStream> {
+    if let Some(socket) = socket {
+        return UnixStream::connect(socket)
+            .with_context(|| format!("Failed to connect to sima-init via {socket}"));
+    }
+
+    let stream = match UnixStream::connect(PRIMARY_SOCKET_PATH) {
         Ok(stream) => stream,
         Err(primary_err) if should_fallback_from_connect_error(&primary_err) => {
             match UnixStream::connect(FALLBACK_SOCKET_PATH) {
@@ -73,6 +291,11 @@ fn send_request(req: Request) -> Result<Response> {
             });
         }
     };
+    Ok(stream)
+}
+
+fn send_request(req: Request, socket: Option<&str>) -> Result<Response> {
+    let mut stream = connect(socket)?;
 
     let data = encode(&req).context("Failed to encode request")?;
     stream.write_all(&data).context("Failed to send request")?;
@@ -89,22 +312,210 @@ fn send_request(req: Request) -> Result<Response> {
     Ok(resp)
 }
 
+fn print_scheduled(shutdown: &ScheduledShutdown) {
+    let at = i64::try_from(shutdown.at)
+        .ok()
+        .and_then(|at| Local.timestamp_opt(at, 0).single());
+    let Some(at) = at else {
+        println!(
+            "Scheduled to {} at {} (Unix time)",
+            shutdown.kind.verb(),
+            shutdown.at
+        );
+        return;
+    };
+    let minutes = (at - Local::now()).num_minutes().max(0);
+    println!(
+        "Scheduled to {} at {} (in {} min)",
+        shutdown.kind.verb(),
+        at.format("%Y-%m-%d %H:%M"),
+        minutes
+    );
+}
+
 fn print_response(resp: Response) {
     match resp {
         Response::Ok => println!("OK"),
         Response::Error(e) => eprintln!("Error: {}", e),
-        Response::StatusReport(services) => {
+        Response::StatusReport(services, shutdown) => {
+            if let Some(shutdown) = &shutdown {
+                print_scheduled(shutdown);
+                println!();
+            }
             if services.is_empty() {
                 println!("No services configured.");
                 return;
             }
-            println!("{:<20} {:>8}  {:>8}", "SERVICE", "STATUS", "PID");
-            println!("{}", "-".repeat(40));
+            println!(
+                "{:<20} {:>8}  {:>8}  {:<8}",
+                "SERVICE", "STATUS", "PID", "ENABLED"
+            );
+            println!("{}", "-".repeat(50));
             for svc in services {
-                let status = if svc.running { "running" } else { "stopped" };
+                let status = if svc.running && svc.mount {
+                    "mounted"
+                } else if svc.running {
+                    "running"
+                } else if svc.failed.is_some() {
+                    "failed"
+                } else if svc.skipped.is_some() {
+                    "skipped"
+                } else {
+                    "stopped"
+                };
                 let pid = svc.pid.map(|p| p.to_string()).unwrap_or_else(|| "-".into());
-                println!("{:<20} {:>8}  {:>8}", svc.name, status, pid);
+                let note = if svc.needs_restart {
+                    "  (needs restart)".to_string()
+                } else if let Some(reason) = &svc.failed {
+                    format!("  ({reason})")
+                } else if let Some(condition) = &svc.skipped {
+                    format!("  (condition not met: {condition})")
+                } else {
+                    String::new()
+                };
+                let enablement = match svc.enablement {
+                    Enablement::Enabled => "enabled",
+                    Enablement::Disabled => "disabled",
+                    Enablement::Masked => "masked",
+                };
+                println!(
+                    "{:<20} {:>8}  {:>8}  {:<8}{}",
+                    svc.name, status, pid, enablement, note
+                );
+            }
+        }
+        Response::Definition(definition) => print!("{definition}"),
+        Response::ConfigReloaded(diff) => {
+            if diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty() {
+                println!("No changes.");
+                return;
+            }
+            for name in &diff.added {
+                println!("added    {name}");
+            }
+            for name in &diff.removed {
+                println!("removed  {name}");
+            }
+            for name in &diff.changed {
+                println!("changed  {name}");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_delay, parse_time_of_day};
+    use chrono::{
+        FixedOffset, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone,
+    };
+
+    /// Central European time on the day clocks go forward: 02:00 to 03:00 is skipped.
+    #[derive(Clone, Copy, Debug)]
+    struct SpringForward;
+
+    impl SpringForward {
+        const WINTER: i32 = 3600;
+        const SUMMER: i32 = 2 * 3600;
+
+        fn switch() -> NaiveDateTime {
+            NaiveDate::from_ymd_opt(2026, 3, 29)
+                .and_then(|date| date.and_hms_opt(1, 0, 0))
+                .expect("switch should be a valid UTC time")
+        }
+
+        fn offset(seconds: i32) -> FixedOffset {
+            FixedOffset::east_opt(seconds).expect("offset should be valid")
+        }
+    }
+
+    impl TimeZone for SpringForward {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            SpringForward
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let gap = Self::switch() + TimeDelta::seconds(Self::WINTER.into());
+            if *local < gap {
+                LocalResult::Single(Self::offset(Self::WINTER))
+            } else if *local < gap + TimeDelta::hours(1) {
+                LocalResult::None
+            } else {
+                LocalResult::Single(Self::offset(Self::SUMMER))
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            Self::offset(if *utc < Self::switch() {
+                Self::WINTER
+            } else {
+                Self::SUMMER
+            })
+        }
+    }
+
+    fn local(day: u32, hour: u32, minute: u32) -> chrono::DateTime<SpringForward> {
+        SpringForward
+            .with_ymd_and_hms(2026, 3, day, hour, minute, 0)
+            .single()
+            .expect("time should exist")
+    }
+
+    #[test]
+    fn parse_delay_accepts_units_and_bare_minutes() {
+        assert_eq!(
+            parse_delay("5").expect("bare number"),
+            TimeDelta::minutes(5)
+        );
+        assert_eq!(
+            parse_delay("1h30m").expect("hours and minutes"),
+            TimeDelta::minutes(90)
+        );
+        assert_eq!(parse_delay("90s").expect("seconds"), TimeDelta::seconds(90));
+        for delay in ["-5", "-1h", "1h-30m", "0s", "1h30", "5x", "", "m"] {
+            assert!(parse_delay(delay).is_err(), "{delay}");
+        }
+    }
+
+    #[test]
+    fn parse_time_of_day_picks_the_next_occurrence() {
+        let now = local(28, 20, 0);
+        assert_eq!(
+            parse_time_of_day("21:30", now).expect("later today"),
+            local(28, 21, 30)
+        );
+        assert_eq!(
+            parse_time_of_day("19:00", now).expect("tomorrow"),
+            local(29, 19, 0)
+        );
+        assert_eq!(
+            parse_time_of_day("20:00", now).expect("now is already past"),
+            local(29, 20, 0)
+        );
+        assert!(parse_time_of_day("25:00", now).is_err());
+        assert!(parse_time_of_day("noon", now).is_err());
+    }
+
+    #[test]
+    fn parse_time_of_day_skips_a_time_lost_to_daylight_saving() {
+        // 02:30 does not exist on the 29th, so the next one is on the 30th.
+        assert_eq!(
+            parse_time_of_day("02:30", local(28, 23, 0)).expect("time should resolve"),
+            local(30, 2, 30)
+        );
+        assert_eq!(
+            parse_time_of_day("03:30", local(28, 23, 0)).expect("time should resolve"),
+            local(29, 3, 30)
+        );
+    }
+}
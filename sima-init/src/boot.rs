@@ -0,0 +1,313 @@
+use crate::console::{self, CONSOLE};
+use crate::shutdown;
+use anyhow::{Context, Result, bail};
+use nix::mount::{MsFlags, mount};
+use nix::net::if_::if_nametoindex;
+use nix::sys::reboot::{RebootMode, set_cad_enabled};
+use nix::sys::socket::{
+    AddressFamily, MsgFlags, SockFlag, SockProtocol, SockType, recv, send, socket,
+};
+use nix::unistd::{gethostname, sethostname};
+use spdlog::{error, info, warn};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+pub const HOSTNAME_PATH: &str = "/etc/hostname";
+pub const MACHINE_ID_PATH: &str = "/etc/machine-id";
+/// Where a machine ID that cannot be saved is kept, bind-mounted over `MACHINE_ID_PATH`.
+const TRANSIENT_MACHINE_ID_PATH: &str = "/run/machine-id";
+const FALLBACK_HOSTNAME: &str = "localhost";
+/// How long emergency mode waits before retrying when there is no console to ask on.
+const NO_CONSOLE_DELAY: Duration = Duration::from_secs(10);
+
+/// Boot steps run by PID 1 before any service starts. Each one logs its own failure so
+/// that the others still run.
+pub fn setup() {
+    // Ctrl-Alt-Del then sends SIGINT to init instead of rebooting on the spot.
+    match set_cad_enabled(false) {
+        Ok(()) => {}
+        // Only the host's init can change it; a container never sees the keystroke.
+        Err(nix::Error::EINVAL) => info!("Not disabling Ctrl-Alt-Del in a PID namespace"),
+        Err(e) => warn!("Failed to disable the Ctrl-Alt-Del reboot: {}", e),
+    }
+    if let Err(e) = set_hostname(Path::new(HOSTNAME_PATH)) {
+        warn!("Failed to set the hostname: {:#}", e);
+    }
+    if let Err(e) = setup_machine_id(Path::new(MACHINE_ID_PATH)) {
+        warn!("Failed to set up the machine ID: {:#}", e);
+    }
+    if let Err(e) = bring_up_loopback() {
+        warn!("Failed to bring up the loopback interface: {:#}", e);
+    }
+}
+
+/// Runs `shell` on the console and waits for it to exit, for debugging a boot.
+pub fn console_shell(shell: &Path, reason: &str) {
+    run_shell(shell, reason, "Exit the shell to continue booting.");
+    info!("Continuing boot");
+}
+
+/// Emergency mode, entered instead of exiting when the boot cannot go on: runs `shell`
+/// on the console, then asks whether to continue, reboot or power off. Returns only to
+/// continue, and the caller retries what failed.
+pub fn emergency(shell: &Path, reason: &str) {
+    loop {
+        run_shell(
+            shell,
+            &format!("Emergency mode: {reason}"),
+            "Exit the shell to continue booting, reboot or power off.",
+        );
+        let mode = loop {
+            let Some(answer) = ask("Continue booting, reboot or power off? [C/r/p] ") else {
+                // Nobody can answer; retry rather than spin.
+                warn!(
+                    "No answer on the console, continuing in {} seconds",
+                    NO_CONSOLE_DELAY.as_secs()
+                );
+                std::thread::sleep(NO_CONSOLE_DELAY);
+                return;
+            };
+            match answer.to_lowercase().as_str() {
+                "" | "c" | "continue" => return,
+                "r" | "reboot" => break RebootMode::RB_AUTOBOOT,
+                "p" | "poweroff" => break RebootMode::RB_POWER_OFF,
+                _ => {}
+            }
+        };
+        let Err(e) = shutdown::finalize(mode, None);
+        error!("Failed to {:?}: {}", mode, e);
+    }
+}
+
+fn open_console() -> Option<File> {
+    console::open().map_err(|e| warn!("{:#}", e)).ok()
+}
+
+/// Without a console, the shell inherits sima's own terminal.
+fn run_shell(shell: &Path, banner: &str, hint: &str) {
+    warn!("{}: starting {} on {}", banner, shell.display(), CONSOLE);
+    let mut command = Command::new(shell);
+    if let Some(mut console) = open_console() {
+        let _ = writeln!(console, "\n{banner}\n{hint}\n");
+        if let (Ok(stdin), Ok(stdout)) = (console.try_clone(), console.try_clone()) {
+            command.stdin(stdin).stdout(stdout).stderr(console);
+        }
+    }
+
+    match command.status() {
+        Ok(status) => info!("Shell exited ({})", status),
+        Err(e) => error!("Failed to start {}: {}", shell.display(), e),
+    }
+}
+
+/// Asks `question` on the console and returns the trimmed answer, or `None` if nothing
+/// can be read.
+fn ask(question: &str) -> Option<String> {
+    let mut answer = String::new();
+    let read = match open_console() {
+        Some(mut console) => {
+            write!(console, "{question}").ok()?;
+            BufReader::new(console).read_line(&mut answer)
+        }
+        None => {
+            print!("{question}");
+            let _ = std::io::stdout().flush();
+            std::io::stdin().read_line(&mut answer)
+        }
+    };
+    matches!(read, Ok(n) if n > 0).then(|| answer.trim().to_string())
+}
+
+/// The hostname in the contents of `/etc/hostname`: the first line that is not blank or
+/// a comment.
+fn parse_hostname(source: &str) -> Option<&str> {
+    source
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Sets the hostname from `path`. Without one, a hostname set earlier (by the kernel
+/// command line or an initramfs) is kept, and `localhost` is used otherwise.
+fn set_hostname(path: &Path) -> Result<()> {
+    let source = fs::read_to_string(path).unwrap_or_default();
+    let hostname = match parse_hostname(&source) {
+        Some(hostname) => hostname,
+        None => {
+            let current = gethostname().context("failed to read the current hostname")?;
+            if !current.is_empty() && current != "(none)" {
+                info!("Keeping hostname {}", current.to_string_lossy());
+                return Ok(());
+            }
+            FALLBACK_HOSTNAME
+        }
+    };
+    sethostname(hostname).with_context(|| format!("failed to set hostname to {hostname}"))?;
+    info!("Hostname set to {}", hostname);
+    Ok(())
+}
+
+fn is_valid_machine_id(id: &str) -> bool {
+    id.len() == 32
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// A random machine ID, from the kernel's random UUID generator.
+fn generate_machine_id() -> Result<String> {
+    let uuid = fs::read_to_string("/proc/sys/kernel/random/uuid")
+        .context("failed to read /proc/sys/kernel/random/uuid")?;
+    let id: String = uuid.trim().chars().filter(|c| *c != '-').collect();
+    if !is_valid_machine_id(&id) {
+        bail!("unexpected random UUID `{}`", uuid.trim());
+    }
+    Ok(id)
+}
+
+/// Keeps the machine ID in `path` if it is valid, or generates one and saves it there.
+/// On a read-only root the new ID is kept in /run and bind-mounted over `path`, so it
+/// lasts until the next boot.
+fn setup_machine_id(path: &Path) -> Result<()> {
+    let existing = fs::read_to_string(path).unwrap_or_default();
+    if is_valid_machine_id(existing.trim()) {
+        info!("Machine ID is {}", existing.trim());
+        return Ok(());
+    }
+
+    let id = generate_machine_id()?;
+    match fs::write(path, format!("{id}\n")) {
+        Ok(()) => {
+            info!("Generated machine ID {}", id);
+            return Ok(());
+        }
+        Err(e) => warn!(
+            "Failed to write {}: {}; using a transient machine ID",
+            path.display(),
+            e
+        ),
+    }
+
+    fs::write(TRANSIENT_MACHINE_ID_PATH, format!("{id}\n"))
+        .with_context(|| format!("failed to write {TRANSIENT_MACHINE_ID_PATH}"))?;
+    mount(
+        Some(TRANSIENT_MACHINE_ID_PATH),
+        path,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )
+    .with_context(|| {
+        format!(
+            "failed to bind {TRANSIENT_MACHINE_ID_PATH} over {}",
+            path.display()
+        )
+    })?;
+    info!("Transient machine ID is {}", id);
+    Ok(())
+}
+
+// From <linux/netlink.h> and <linux/rtnetlink.h>.
+const RTM_NEWLINK: u16 = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const IFF_UP: u32 = 0x1;
+const NLMSG_HDRLEN: usize = 16;
+
+/// An `RTM_NEWLINK` request setting `IFF_UP` on interface `index`: a `nlmsghdr` followed by
+/// an `ifinfomsg`, in native byte order.
+fn link_up_request(index: u32) -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(&32u32.to_ne_bytes()); // nlmsg_len
+    message.extend_from_slice(&RTM_NEWLINK.to_ne_bytes()); // nlmsg_type
+    message.extend_from_slice(&(NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes()); // nlmsg_flags
+    message.extend_from_slice(&1u32.to_ne_bytes()); // nlmsg_seq
+    message.extend_from_slice(&0u32.to_ne_bytes()); // nlmsg_pid
+    message.extend_from_slice(&[0, 0]); // ifi_family (AF_UNSPEC), padding
+    message.extend_from_slice(&0u16.to_ne_bytes()); // ifi_type
+    message.extend_from_slice(&index.to_ne_bytes()); // ifi_index
+    message.extend_from_slice(&IFF_UP.to_ne_bytes()); // ifi_flags
+    message.extend_from_slice(&IFF_UP.to_ne_bytes()); // ifi_change
+    message
+}
+
+/// The errno carried by a netlink acknowledgement, 0 on success.
+fn parse_ack(reply: &[u8]) -> Result<i32> {
+    let kind = u16::from_ne_bytes(reply_bytes(reply, 4)?);
+    if kind != NLMSG_ERROR {
+        bail!("unexpected netlink reply of type {kind}");
+    }
+    Ok(-i32::from_ne_bytes(reply_bytes(reply, NLMSG_HDRLEN)?))
+}
+
+fn reply_bytes<const N: usize>(reply: &[u8], offset: usize) -> Result<[u8; N]> {
+    reply
+        .get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("netlink reply is too short")
+}
+
+/// Sets `lo` up through rtnetlink. The kernel adds 127.0.0.1 and ::1 to it by itself.
+fn bring_up_loopback() -> Result<()> {
+    let index = if_nametoindex("lo").context("no loopback interface")?;
+    let fd = socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkRoute,
+    )
+    .context("failed to open a netlink socket")?;
+
+    send(fd.as_raw_fd(), &link_up_request(index), MsgFlags::empty())
+        .context("failed to send the netlink request")?;
+    let mut reply = [0u8; 1024];
+    let len = recv(fd.as_raw_fd(), &mut reply, MsgFlags::empty())
+        .context("failed to read the netlink reply")?;
+    match parse_ack(&reply[..len])? {
+        0 => {
+            info!("Loopback interface lo is up");
+            Ok(())
+        }
+        errno => Err(nix::errno::Errno::from_raw(errno)).context("the kernel refused"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_machine_id, link_up_request, parse_ack, parse_hostname};
+
+    #[test]
+    fn parse_hostname_skips_comments_and_blank_lines() {
+        assert_eq!(
+            parse_hostname("# set by installer\n\n  kiosk-01 \n"),
+            Some("kiosk-01")
+        );
+        assert_eq!(parse_hostname("# nothing\n"), None);
+    }
+
+    #[test]
+    fn machine_id_is_32_lowercase_hex_digits() {
+        assert!(is_valid_machine_id("0123456789abcdef0123456789abcdef"));
+        assert!(!is_valid_machine_id("0123456789ABCDEF0123456789ABCDEF"));
+        assert!(!is_valid_machine_id("uninitialized"));
+        assert!(!is_valid_machine_id(""));
+    }
+
+    #[test]
+    fn netlink_messages_round_trip() {
+        let request = link_up_request(1);
+        assert_eq!(request.len(), 32);
+        assert_eq!(&request[20..24], &1u32.to_ne_bytes());
+
+        let mut ack = vec![0u8; 36];
+        ack[4..6].copy_from_slice(&2u16.to_ne_bytes());
+        ack[16..20].copy_from_slice(&(-1i32).to_ne_bytes());
+        assert_eq!(parse_ack(&ack).expect("ack should parse"), 1);
+        assert!(parse_ack(&ack[..8]).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use crate::condition::is_executable;
+use crate::config::{SERVICE_DIRS, ServiceConfig, SimaConfig};
+use crate::dropin::FragmentError;
+use crate::format::{self, Format};
+use crate::sandbox::Sandbox;
+use crate::service::parse_environment;
+use crate::target;
+use crate::template;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin:/usr/local/sbin:/usr/sbin:/sbin";
+
+/// A problem found in a configuration file.
+#[derive(Debug, PartialEq)]
+pub struct ConfigIssue {
+    pub path: PathBuf,
+    /// 1-based line and column, when known.
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location {
+            Some((line, column)) => write!(
+                f,
+                "{}:{}:{}: {}",
+                self.path.display(),
+                line,
+                column,
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl ConfigIssue {
+    fn new(path: &Path, message: impl Into<String>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            location: None,
+            message: message.into(),
+        }
+    }
+
+    /// Points the issue at the key at the end of `path` in its file.
+    fn at_key(self, path: &[&str]) -> Self {
+        self.at(path, true)
+    }
+
+    /// Points the issue at the value at the end of `path` in its file.
+    fn at_value(self, path: &[&str]) -> Self {
+        self.at(path, false)
+    }
+
+    fn at(mut self, path: &[&str], key: bool) -> Self {
+        let format = Format::of(&self.path);
+        self.location = fs::read_to_string(&self.path).ok().and_then(|source| {
+            if key {
+                format.locate_key(&source, path)
+            } else {
+                format.locate_value(&source, path)
+            }
+        });
+        self
+    }
+
+    fn from_error(path: &Path, err: &anyhow::Error) -> Self {
+        let path = err
+            .downcast_ref::<FragmentError>()
+            .map_or(path, |fragment| fragment.0.as_path());
+
+        let source = fs::read_to_string(path).unwrap_or_default();
+        let (location, message) = match format::location(err, &source) {
+            Some((location, message)) => (Some(location), message),
+            None => (None, err.root_cause().to_string()),
+        };
+
+        Self {
+            path: path.to_path_buf(),
+            location,
+            message,
+        }
+    }
+}
+
+/// Validates the manifest and every service file it references, collecting all issues
+/// instead of stopping at the first one.
+pub fn check_config(manifest_path: &Path) -> Vec<ConfigIssue> {
+    check_config_with(manifest_path, &SERVICE_DIRS)
+}
+
+fn check_config_with<D: AsRef<Path>>(manifest_path: &Path, service_dirs: &[D]) -> Vec<ConfigIssue> {
+    let groups = match SimaConfig::service_files(manifest_path, service_dirs) {
+        Ok(groups) => groups,
+        Err(err) => return vec![ConfigIssue::from_error(manifest_path, &err)],
+    };
+
+    let mut issues = Vec::new();
+    let mut names = HashSet::new();
+    for group in groups {
+        let mut seen: HashMap<String, PathBuf> = HashMap::new();
+        for path in group {
+            let service = match SimaConfig::load_service(&path, service_dirs) {
+                Ok(service) => service,
+                Err(err) => {
+                    issues.push(ConfigIssue::from_error(&path, &err));
+                    continue;
+                }
+            };
+
+            names.insert(service.name.clone());
+            if let Some(first) = seen.get(&service.name) {
+                issues.push(
+                    ConfigIssue::new(
+                        &path,
+                        format!(
+                            "duplicate service name `{}` (also defined in {})",
+                            service.name,
+                            first.display()
+                        ),
+                    )
+                    .at_key(&["name"]),
+                );
+            } else {
+                seen.insert(service.name.clone(), path.clone());
+            }
+
+            for key in SimaConfig::unknown_service_keys(&path).unwrap_or_default() {
+                issues
+                    .push(ConfigIssue::new(&path, format!("unknown key `{key}`")).at_key(&[&key]));
+            }
+            check_service(&path, &service, &mut issues);
+        }
+    }
+
+    for key in SimaConfig::unknown_manifest_keys(manifest_path).unwrap_or_default() {
+        let key: Vec<&str> = key.iter().map(String::as_str).collect();
+        issues.push(
+            ConfigIssue::new(manifest_path, format!("unknown key `{}`", key.join(".")))
+                .at_key(&key),
+        );
+    }
+    check_targets(manifest_path, &names, &mut issues);
+    issues
+}
+
+fn check_targets(manifest_path: &Path, names: &HashSet<String>, issues: &mut Vec<ConfigIssue>) {
+    // Manifest parse errors were already reported while listing service files.
+    let Ok((targets, default_target)) = SimaConfig::targets(manifest_path) else {
+        return;
+    };
+    let is_known = |service: &str| {
+        names.contains(service)
+            || template::split_instance(service).is_some_and(|(t, _)| names.contains(t))
+    };
+
+    for (name, target) in &targets {
+        for (index, required) in target.requires.iter().enumerate() {
+            if !targets.contains_key(required) {
+                issues.push(
+                    ConfigIssue::new(
+                        manifest_path,
+                        format!("target `{name}` requires undefined target `{required}`"),
+                    )
+                    .at_value(&[
+                        "targets",
+                        name,
+                        "requires",
+                        &index.to_string(),
+                    ]),
+                );
+            }
+        }
+        for (index, service) in target.services.iter().enumerate() {
+            if !is_known(service) {
+                issues.push(
+                    ConfigIssue::new(
+                        manifest_path,
+                        format!("target `{name}` wants unknown service `{service}`"),
+                    )
+                    .at_value(&[
+                        "targets",
+                        name,
+                        "services",
+                        &index.to_string(),
+                    ]),
+                );
+            }
+        }
+    }
+
+    for cycle in target::cycles(&targets) {
+        issues.push(
+            ConfigIssue::new(
+                manifest_path,
+                format!("targets form a dependency cycle: {}", cycle.join(" -> ")),
+            )
+            .at_key(&["targets", &cycle[0]]),
+        );
+    }
+
+    if let Some(name) = default_target
+        && !targets.contains_key(&name)
+    {
+        issues.push(
+            ConfigIssue::new(
+                manifest_path,
+                format!("default target `{name}` is not defined"),
+            )
+            .at_value(&["default_target"]),
+        );
+    }
+}
+
+fn check_service(path: &Path, service: &ServiceConfig, issues: &mut Vec<ConfigIssue>) {
+    if let Some(environment) = &service.environment {
+        for (index, entry) in environment.iter().enumerate() {
+            if let Err(err) = parse_environment(std::slice::from_ref(entry)) {
+                issues.push(
+                    ConfigIssue::new(path, err.to_string())
+                        .at_value(&["environment", &index.to_string()]),
+                );
+            }
+        }
+    }
+
+    if let Err(err) = Sandbox::from_config(service) {
+        issues.push(ConfigIssue::new(path, err.to_string()).at_key(&["bind_paths"]));
+    }
+
+    for (index, mount_point) in service.requires_mounts_for.iter().enumerate() {
+        if !mount_point.is_absolute() {
+            issues.push(
+                ConfigIssue::new(
+                    path,
+                    format!("`{}` is not an absolute path", mount_point.display()),
+                )
+                .at_value(&["requires_mounts_for", &index.to_string()]),
+            );
+        }
+    }
+
+    let Some(program) = service.cmdline.split_whitespace().next() else {
+        issues.push(ConfigIssue::new(path, "cmdline is empty").at_value(&["cmdline"]));
+        return;
+    };
+    // Anything the shell or a template expands cannot be resolved statically.
+    if program.contains(['$', '`', '=', '%']) {
+        return;
+    }
+    let search_path = service
+        .environment
+        .iter()
+        .flatten()
+        .find_map(|entry| entry.strip_prefix("PATH="))
+        .unwrap_or(DEFAULT_PATH);
+    if find_executable(program, search_path).is_none() {
+        issues.push(
+            ConfigIssue::new(path, format!("executable `{program}` not found"))
+                .at_value(&["cmdline"]),
+        );
+    }
+}
+
+fn find_executable(program: &str, search_path: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+    search_path
+        .split(':')
+        .map(|dir| Path::new(dir).join(program))
+        .find(|path| is_executable(path))
+}
+
+/// Implements `sima-init --check-config`; returns the process exit code.
+pub fn run(manifest_path: &Path) -> i32 {
+    let issues = check_config(manifest_path);
+    for issue in &issues {
+        eprintln!("{issue}");
+    }
+
+    if issues.is_empty() {
+        println!("{}: configuration OK", manifest_path.display());
+        0
+    } else {
+        eprintln!("{} problem(s) found", issues.len());
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigIssue, check_config_with, find_executable};
+    use crate::testutil::{scratch_dir, write_service};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn check_config_collects_issues_across_files() {
+        let root = scratch_dir("collect");
+        let dir = root.join("sima.d");
+        fs::create_dir_all(&dir).expect("service dir should be created");
+        fs::write(
+            dir.join("a.yml"),
+            "name: a\ncmdline: /bin/sh\nrestart: always\n",
+        )
+        .expect("service file should be written");
+        fs::write(
+            dir.join("b.yml"),
+            "name: b\ncmdline: /bin/sh\nenvironment:\n  - BROKEN\n",
+        )
+        .expect("service file should be written");
+        write_service(&dir, "c.yml", "b", "/nonexistent/daemon");
+
+        let issues = check_config_with(&root.join("missing.yml"), &[&dir]);
+
+        let summary: Vec<_> = issues
+            .iter()
+            .map(|issue| {
+                (
+                    issue
+                        .path
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .unwrap_or(""),
+                    issue.location,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("a.yml", Some((3, 1))),
+                ("b.yml", Some((4, 5))),
+                ("c.yml", Some((1, 1))),
+                ("c.yml", Some((2, 10))),
+            ]
+        );
+        assert_eq!(issues[0].message, "unknown key `restart`");
+        assert!(issues[1].message.contains("expected KEY=VALUE"));
+        assert!(issues[2].message.contains("duplicate service name `b`"));
+        assert!(
+            issues[3]
+                .message
+                .contains("`/nonexistent/daemon` not found")
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn check_config_reports_target_errors_and_cycles() {
+        let root = scratch_dir("targets");
+        let dir = root.join("sima.d");
+        write_service(&dir, "getty@.yml", "getty@", "/bin/sh");
+        let manifest = root.join("sima.yml");
+        fs::write(
+            &manifest,
+            format!(
+                "include: ['{}/*.yml']\ndefault_target: graphical\ntargets:\n  rescue:\n    services: [getty@tty1, ghost]\n    requires: [multi-user]\n  multi-user:\n    requires: [rescue, basic]\n",
+                dir.display()
+            ),
+        )
+        .expect("manifest should be written");
+
+        let issues = check_config_with(&manifest, &[root.join("unused")]);
+
+        let messages: Vec<_> = issues.iter().map(|issue| issue.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "target `multi-user` requires undefined target `basic`",
+                "target `rescue` wants unknown service `ghost`",
+                "targets form a dependency cycle: multi-user -> rescue -> multi-user",
+                "default target `graphical` is not defined",
+            ]
+        );
+        assert_eq!(issues[2].location, Some((7, 3)));
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn config_issue_display_includes_location() {
+        let issue = ConfigIssue {
+            path: PathBuf::from("/etc/sima.d/a.yml"),
+            location: Some((3, 7)),
+            message: "invalid type".to_string(),
+        };
+
+        assert_eq!(issue.to_string(), "/etc/sima.d/a.yml:3:7: invalid type");
+    }
+
+    #[test]
+    fn find_executable_searches_path() {
+        assert_eq!(
+            find_executable("sh", "/nonexistent:/bin"),
+            Some(Path::new("/bin/sh").into())
+        );
+        assert_eq!(find_executable("sh", "/nonexistent"), None);
+    }
+}
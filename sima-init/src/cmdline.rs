@@ -0,0 +1,130 @@
+use std::fs;
+
+const PROC_CMDLINE: &str = "/proc/cmdline";
+
+/// Parameters from the kernel command line, in the order they appear.
+#[derive(Debug, Default)]
+pub struct KernelCmdline {
+    params: Vec<(String, Option<String>)>,
+}
+
+impl KernelCmdline {
+    /// Reads /proc/cmdline; an unreadable command line is treated as empty.
+    pub fn load() -> Self {
+        fs::read_to_string(PROC_CMDLINE)
+            .map(|cmdline| Self::parse(&cmdline))
+            .unwrap_or_default()
+    }
+
+    pub fn parse(cmdline: &str) -> Self {
+        let params = split_words(cmdline)
+            .into_iter()
+            .map(|word| match word.split_once('=') {
+                Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                None => (word, None),
+            })
+            .collect();
+        Self { params }
+    }
+
+    /// Value of the last `key=value` occurrence of `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Values of every `key=value` occurrence of `key`, with comma-separated lists split.
+    pub fn values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.params
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .filter_map(|(_, value)| value.as_deref())
+            .flat_map(|value| value.split(','))
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Whether `word` appears: a bare word matches the parameter with any value, while
+    /// `key=value` has to match exactly.
+    pub fn contains(&self, word: &str) -> bool {
+        match word.split_once('=') {
+            Some((key, value)) => self
+                .params
+                .iter()
+                .any(|(k, v)| k == key && v.as_deref() == Some(value)),
+            None => self.params.iter().any(|(k, _)| k == word),
+        }
+    }
+
+    /// Whether `key` is present, either bare or with a truthy value.
+    pub fn flag(&self, key: &str) -> bool {
+        match self.params.iter().rev().find(|(k, _)| k == key) {
+            Some((_, None)) => true,
+            Some((_, Some(value))) => !matches!(value.as_str(), "0" | "no" | "false" | "off"),
+            None => false,
+        }
+    }
+}
+
+/// Splits on whitespace, keeping double-quoted sections together as the kernel does.
+fn split_words(cmdline: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in cmdline.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    words.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KernelCmdline;
+
+    #[test]
+    fn kernel_cmdline_parses_values_and_flags() {
+        let cmdline = KernelCmdline::parse(
+            "root=/dev/sda1 ro sima.config=/etc/alt.yml sima.verbose quiet sima.config=/etc/last.yml\n",
+        );
+
+        assert_eq!(cmdline.get("root"), Some("/dev/sda1"));
+        assert_eq!(cmdline.get("sima.config"), Some("/etc/last.yml"));
+        assert_eq!(cmdline.get("ro"), None);
+        assert!(cmdline.flag("sima.verbose"));
+        assert!(cmdline.flag("ro"));
+        assert!(!cmdline.flag("sima.missing"));
+    }
+
+    #[test]
+    fn kernel_cmdline_collects_repeated_lists() {
+        let cmdline = KernelCmdline::parse("sima.mask=a,b sima.wants=c sima.mask=d,");
+
+        assert_eq!(
+            cmdline.values("sima.mask").collect::<Vec<_>>(),
+            ["a", "b", "d"]
+        );
+        assert_eq!(cmdline.values("sima.wants").collect::<Vec<_>>(), ["c"]);
+    }
+
+    #[test]
+    fn kernel_cmdline_keeps_quoted_values_together() {
+        let cmdline = KernelCmdline::parse(r#"sima.log_dir="/var/log/my logs" sima.verbose=0"#);
+
+        assert_eq!(cmdline.get("sima.log_dir"), Some("/var/log/my logs"));
+        assert!(!cmdline.flag("sima.verbose"));
+    }
+}
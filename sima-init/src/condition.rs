@@ -0,0 +1,230 @@
+use crate::cmdline::KernelCmdline;
+use crate::mounts::is_mount_point;
+use platform_info::{PlatformInfo, PlatformInfoAPI, UNameAPI};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// A check made before starting a service. An unmet condition skips the service; an unmet
+/// assertion marks it failed.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Condition {
+    PathExists(String),
+    PathIsMountPoint(String),
+    DirectoryNotEmpty(String),
+    FileIsExecutable(String),
+    /// A bare word matches the parameter with any value; `key=value` must match exactly.
+    KernelCommandLine(String),
+    /// Machine name as reported by `uname -m`, e.g. `x86_64` or `aarch64`.
+    Architecture(String),
+    /// `vm`, `container`, or a technology such as `kvm`, `docker` or `podman`.
+    Virtualization(String),
+    Not(Box<Condition>),
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::PathExists(path) => write!(f, "path_exists {path}"),
+            Condition::PathIsMountPoint(path) => write!(f, "path_is_mount_point {path}"),
+            Condition::DirectoryNotEmpty(path) => write!(f, "directory_not_empty {path}"),
+            Condition::FileIsExecutable(path) => write!(f, "file_is_executable {path}"),
+            Condition::KernelCommandLine(word) => write!(f, "kernel_command_line {word}"),
+            Condition::Architecture(arch) => write!(f, "architecture {arch}"),
+            Condition::Virtualization(kind) => write!(f, "virtualization {kind}"),
+            Condition::Not(inner) => write!(f, "not {inner}"),
+        }
+    }
+}
+
+impl Condition {
+    pub fn holds(&self, host: &Host) -> bool {
+        match self {
+            Condition::PathExists(path) => Path::new(path).exists(),
+            Condition::PathIsMountPoint(path) => is_mount_point(Path::new(path)),
+            Condition::DirectoryNotEmpty(path) => fs::read_dir(path)
+                .map(|mut entries| entries.next().is_some())
+                .unwrap_or(false),
+            Condition::FileIsExecutable(path) => is_executable(Path::new(path)),
+            Condition::KernelCommandLine(word) => host.cmdline.contains(word),
+            Condition::Architecture(arch) => host.architecture == *arch,
+            Condition::Virtualization(kind) => {
+                host.virtualization
+                    .as_ref()
+                    .is_some_and(|virt| match kind.as_str() {
+                        "vm" => !virt.container,
+                        "container" => virt.container,
+                        name => virt.name == name,
+                    })
+            }
+            Condition::Not(inner) => !inner.holds(host),
+        }
+    }
+
+    /// Applies `expand` to the value of the condition, for template specifiers.
+    pub fn map_value(&self, expand: &impl Fn(&str) -> String) -> Self {
+        match self {
+            Condition::PathExists(v) => Condition::PathExists(expand(v)),
+            Condition::PathIsMountPoint(v) => Condition::PathIsMountPoint(expand(v)),
+            Condition::DirectoryNotEmpty(v) => Condition::DirectoryNotEmpty(expand(v)),
+            Condition::FileIsExecutable(v) => Condition::FileIsExecutable(expand(v)),
+            Condition::KernelCommandLine(v) => Condition::KernelCommandLine(expand(v)),
+            Condition::Architecture(v) => Condition::Architecture(expand(v)),
+            Condition::Virtualization(v) => Condition::Virtualization(expand(v)),
+            Condition::Not(inner) => Condition::Not(Box::new(inner.map_value(expand))),
+        }
+    }
+}
+
+/// The first of `conditions` that does not hold on `host`.
+pub fn first_unmet<'a>(conditions: &'a [Condition], host: &Host) -> Option<&'a Condition> {
+    conditions.iter().find(|condition| !condition.holds(host))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Virtualization {
+    pub container: bool,
+    pub name: String,
+}
+
+/// Facts about the machine that conditions are checked against, gathered once at startup.
+#[derive(Debug, Default)]
+pub struct Host {
+    pub cmdline: KernelCmdline,
+    pub architecture: String,
+    pub virtualization: Option<Virtualization>,
+}
+
+impl Host {
+    pub fn detect() -> Self {
+        Self {
+            cmdline: KernelCmdline::load(),
+            architecture: PlatformInfo::new()
+                .map(|info| info.machine().to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            virtualization: detect_container().or_else(detect_vm),
+        }
+    }
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+fn detect_container() -> Option<Virtualization> {
+    // Container managers set `container=` in the environment of the container's PID 1.
+    let name = std::env::var("container")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .or_else(|| {
+            Path::new("/run/.containerenv")
+                .exists()
+                .then(|| "podman".to_string())
+        })
+        .or_else(|| {
+            Path::new("/.dockerenv")
+                .exists()
+                .then(|| "docker".to_string())
+        })?;
+    Some(Virtualization {
+        container: true,
+        name,
+    })
+}
+
+fn detect_vm() -> Option<Virtualization> {
+    const DMI_VENDORS: [(&str, &str); 9] = [
+        ("KVM", "kvm"),
+        ("QEMU", "qemu"),
+        ("VMware", "vmware"),
+        ("VirtualBox", "oracle"),
+        ("innotek", "oracle"),
+        ("Xen", "xen"),
+        ("Microsoft Corporation", "microsoft"),
+        ("Amazon EC2", "amazon"),
+        ("Parallels", "parallels"),
+    ];
+
+    let dmi = ["sys_vendor", "product_name", "board_vendor"]
+        .iter()
+        .filter_map(|field| fs::read_to_string(format!("/sys/class/dmi/id/{field}")).ok())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let name = DMI_VENDORS
+        .iter()
+        .find(|(vendor, _)| dmi.contains(vendor))
+        .map(|(_, name)| name.to_string())
+        .or_else(|| {
+            fs::read_to_string("/sys/hypervisor/type")
+                .ok()
+                .map(|kind| kind.trim().to_string())
+        })
+        .or_else(|| {
+            // Some hypervisors only show up as a CPU flag.
+            fs::read_to_string("/proc/cpuinfo")
+                .ok()
+                .filter(|cpuinfo| {
+                    cpuinfo
+                        .lines()
+                        .filter(|line| line.starts_with("flags"))
+                        .any(|line| line.split_whitespace().any(|flag| flag == "hypervisor"))
+                })
+                .map(|_| "vm".to_string())
+        })?;
+    Some(Virtualization {
+        container: false,
+        name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Condition, Host, Virtualization, first_unmet};
+    use crate::cmdline::KernelCmdline;
+
+    #[test]
+    fn conditions_check_host_facts() {
+        let host = Host {
+            cmdline: KernelCmdline::parse("root=/dev/sda1 quiet sima.variant=kiosk"),
+            architecture: "aarch64".to_string(),
+            virtualization: Some(Virtualization {
+                container: true,
+                name: "podman".to_string(),
+            }),
+        };
+        let conditions: Vec<Condition> = serde_yaml::with::singleton_map_recursive::deserialize(
+            serde_yaml::Deserializer::from_str(
+                r#"
+- kernel_command_line: quiet
+- kernel_command_line: sima.variant=kiosk
+- architecture: aarch64
+- virtualization: container
+- virtualization: podman
+- path_exists: /
+- directory_not_empty: /proc
+- not:
+    virtualization: vm
+"#,
+            ),
+        )
+        .expect("conditions should parse");
+
+        assert_eq!(first_unmet(&conditions, &host), None);
+
+        let unmet = [
+            Condition::KernelCommandLine("sima.variant=dev".to_string()),
+            Condition::Architecture("x86_64".to_string()),
+        ];
+        assert_eq!(
+            first_unmet(&unmet, &host).map(ToString::to_string),
+            Some("kernel_command_line sima.variant=dev".to_string())
+        );
+        assert!(!Condition::PathExists("/nonexistent".to_string()).holds(&host));
+        assert!(Condition::FileIsExecutable("/bin/sh".to_string()).holds(&host));
+    }
+}
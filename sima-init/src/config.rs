@@ -1,53 +1,466 @@
-use anyhow::Result;
+use crate::condition::Condition;
+use crate::dropin::{self, FragmentError, Sources};
+use crate::format::{self, Format};
+use crate::mounts::{self, MountConfig};
+use crate::signals::{self, SignalAction, SignalTable};
+use crate::target::{TargetConfig, Targets};
+use crate::template;
+use anyhow::{Context, Result, bail};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use serde_yaml::{Mapping, Value};
+use spdlog::{error, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+pub const MANIFEST_PATH: &str = "/etc/sima.yml";
+/// Directories scanned in discovery mode, lowest priority first.
+pub const SERVICE_DIRS: [&str; 2] = ["/usr/lib/sima.d", "/etc/sima.d"];
+/// Name of the service running the main command of a container entrypoint.
+pub const ENTRYPOINT_SERVICE: &str = "main";
+
+/// Unknown keys are ignored when loading, so that one stray key does not keep a service
+/// from starting; `--check-config` reports them.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ServiceConfig {
     pub name: String,
     pub description: Option<String>,
     pub cmdline: String,
     pub environment: Option<Vec<String>>,
+    /// Start the service at boot unless disabled with `simactl disable`.
+    #[serde(default = "default_autostart")]
+    pub autostart: bool,
+    /// Checked before each start; the service is skipped if one does not hold.
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub conditions: Vec<Condition>,
+    /// Like `conditions`, but the service is marked failed instead of skipped.
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub assertions: Vec<Condition>,
+    /// Mount a private tmpfs on /tmp and /var/tmp.
+    #[serde(default)]
+    pub private_tmp: bool,
+    #[serde(default)]
+    pub read_only_paths: Vec<PathBuf>,
+    /// Paths kept writable even when covered by `read_only_paths` or `protect_system`.
+    #[serde(default)]
+    pub read_write_paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub inaccessible_paths: Vec<PathBuf>,
+    /// Bind mounts in `SOURCE[:DEST]` form; `DEST` defaults to `SOURCE`.
+    #[serde(default)]
+    pub bind_paths: Vec<String>,
+    pub protect_system: Option<ProtectSystem>,
+    /// Mount points from /etc/fstab that are mounted before the service starts, e.g.
+    /// `/data` for a service that keeps its files in `/data/db`.
+    #[serde(default)]
+    pub requires_mounts_for: Vec<PathBuf>,
+    /// Program and arguments run directly instead of `cmdline`, without a shell. Only
+    /// set for the main command of a container entrypoint.
+    #[serde(skip)]
+    pub argv: Vec<String>,
+}
+
+impl ServiceConfig {
+    /// Top-level keys of `definition` that are not fields of a service.
+    pub fn unknown_keys(definition: &Value) -> Vec<String> {
+        unknown_keys(&Self::entrypoint(Vec::new()), definition)
+    }
+
+    /// The main command of a container entrypoint (`sima-init -- COMMAND`).
+    pub fn entrypoint(argv: Vec<String>) -> Self {
+        Self {
+            name: ENTRYPOINT_SERVICE.to_string(),
+            description: Some("Main command".to_string()),
+            cmdline: argv.join(" "),
+            environment: None,
+            autostart: true,
+            conditions: Vec::new(),
+            assertions: Vec::new(),
+            private_tmp: false,
+            read_only_paths: Vec::new(),
+            read_write_paths: Vec::new(),
+            inaccessible_paths: Vec::new(),
+            bind_paths: Vec::new(),
+            protect_system: None,
+            requires_mounts_for: Vec::new(),
+            argv,
+        }
+    }
+}
+
+fn default_autostart() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtectSystem {
+    /// /usr, /boot and /etc are read-only.
+    Full,
+    /// The whole file system is read-only except /dev, /proc and /sys.
+    Strict,
 }
 
 #[derive(Debug, Clone)]
 pub struct SimaConfig {
     pub services: Vec<ServiceConfig>,
+    /// Template services (`name@`), instantiated on demand.
+    pub templates: Vec<ServiceConfig>,
+    pub targets: Targets,
+    /// Target started at boot; without one, every enabled service is started.
+    pub default_target: Option<String>,
+    /// Actions for signals sent to sima, overriding the defaults.
+    pub signals: SignalTable,
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize, Serialize)]
 struct Manifest {
+    #[serde(default)]
     services: Vec<String>,
+    /// Glob patterns of service files, e.g. `/etc/sima.d/*.yml`.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Load every service file found in the service directories.
+    #[serde(default)]
+    discover: bool,
+    /// Template instances to load at boot, e.g. `getty@tty1`.
+    #[serde(default)]
+    instances: Vec<String>,
+    #[serde(default)]
+    targets: Targets,
+    default_target: Option<String>,
+    /// File systems mounted before anything else when running as PID 1.
+    early_mounts: Option<Vec<MountConfig>>,
+    /// Signal names such as `SIGPWR` or `SIGRTMIN+4` mapped to actions.
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    signals: BTreeMap<String, SignalAction>,
+}
+
+impl Manifest {
+    /// Keys of the manifest `value` that sima does not know, as paths from the top, e.g.
+    /// `["targets", "rescue", "wants"]`.
+    fn unknown_keys(value: &Value) -> Vec<Vec<String>> {
+        let mut paths: Vec<Vec<String>> = unknown_keys(&Manifest::default(), value)
+            .into_iter()
+            .map(|key| vec![key])
+            .collect();
+        if let Some(Value::Mapping(targets)) = value.get("targets") {
+            for (name, target) in targets {
+                let Some(name) = name.as_str() else {
+                    continue;
+                };
+                for key in unknown_keys(&TargetConfig::default(), target) {
+                    paths.push(vec!["targets".to_string(), name.to_string(), key]);
+                }
+            }
+        }
+        if let Some(Value::Sequence(early_mounts)) = value.get("early_mounts") {
+            let sample = &mounts::default_early_mounts()[0];
+            for (index, mount) in early_mounts.iter().enumerate() {
+                for key in unknown_keys(sample, mount) {
+                    paths.push(vec!["early_mounts".to_string(), index.to_string(), key]);
+                }
+            }
+        }
+        paths
+    }
+
+    fn read(manifest_path: &Path) -> Result<Self> {
+        match fs::read_to_string(manifest_path) {
+            Ok(source) => Format::of(manifest_path)
+                .parse(&source)
+                .with_context(|| format!("failed to parse {}", manifest_path.display())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self {
+                discover: true,
+                ..Default::default()
+            }),
+            Err(err) => {
+                Err(err).with_context(|| format!("failed to open {}", manifest_path.display()))
+            }
+        }
+    }
+
+    fn service_files<D: AsRef<Path>>(&self, service_dirs: &[D]) -> Result<Vec<Vec<PathBuf>>> {
+        let mut groups = Vec::new();
+        if self.discover {
+            for dir in service_dirs {
+                groups.push(scan_service_dir(dir.as_ref())?);
+            }
+        }
+        let mut included = Vec::new();
+        for pattern in &self.include {
+            included.extend(expand_include(pattern)?);
+        }
+        groups.push(included);
+        groups.push(self.services.iter().map(PathBuf::from).collect());
+
+        Ok(groups)
+    }
 }
 
 impl SimaConfig {
-    fn load_service<P: AsRef<Path>>(path: P) -> Result<ServiceConfig> {
-        let file = File::open(path)?;
-        let config = serde_yaml::from_reader(BufReader::new(file))?;
-        Ok(config)
+    /// Loads a service file and applies its drop-ins from the file's own directory and
+    /// `dropin_dirs`, in increasing priority.
+    pub fn load_service<D: AsRef<Path>>(path: &Path, dropin_dirs: &[D]) -> Result<ServiceConfig> {
+        Ok(Self::load_service_with_sources(path, dropin_dirs)?.0)
     }
 
-    pub fn load() -> Result<Self> {
-        let manifest_path = "/etc/sima.yml";
+    fn load_service_with_sources<D: AsRef<Path>>(
+        path: &Path,
+        dropin_dirs: &[D],
+    ) -> Result<(ServiceConfig, Mapping, Sources)> {
+        let source = fs::read_to_string(path)?;
+        let format = Format::of(path);
+        // Deserialize the base file on its own first so errors keep their locations.
+        let base: ServiceConfig = format.parse(&source)?;
+        let mut merged: Mapping = format.parse(&source)?;
+        let mut sources = dropin::initial_sources(&merged, path);
 
-        let file = File::open(manifest_path)?;
-        let manifest: Manifest = serde_yaml::from_reader(BufReader::new(file))?;
+        let mut dirs: Vec<&Path> = path.parent().into_iter().collect();
+        for dir in dropin_dirs {
+            if !dirs.contains(&dir.as_ref()) {
+                dirs.push(dir.as_ref());
+            }
+        }
+        let fragments = dropin::find_dropins(&base.name, &dirs)?;
+        if fragments.is_empty() {
+            return Ok((base, merged, sources));
+        }
 
-        let services = manifest
-            .services
+        for fragment_path in fragments {
+            dropin::apply(&mut merged, &fragment_path, &mut sources)
+                .with_context(|| FragmentError(fragment_path.clone()))?;
+        }
+
+        let config = serde_yaml::from_value(Value::Mapping(merged.clone()))
+            .context("invalid definition after applying drop-ins")?;
+        Ok((config, merged, sources))
+    }
+
+    /// The merged definition of service `name` as YAML, annotated with the file each
+    /// value came from.
+    pub fn describe(manifest_path: &Path, name: &str) -> Result<String> {
+        let mut found = None;
+        for path in Self::service_files(manifest_path, &SERVICE_DIRS)?
             .into_iter()
-            .map(|path| Self::load_service(&path))
-            .collect::<Result<Vec<ServiceConfig>>>()?;
+            .flatten()
+        {
+            let (config, merged, sources) =
+                Self::load_service_with_sources(&path, &SERVICE_DIRS)
+                    .with_context(|| format!("failed to load service {}", path.display()))?;
+            if config.name == name {
+                found = Some((merged, sources));
+            }
+        }
+
+        let Some((merged, sources)) = found else {
+            bail!("service {name} not found");
+        };
+        dropin::render(&merged, &sources)
+    }
+
+    pub fn load(manifest_path: &Path) -> Result<Self> {
+        Self::load_from(manifest_path, &SERVICE_DIRS)
+    }
+
+    /// Loads services from discovery directories, `include` globs and the explicit
+    /// `services` list, in that order. A later definition replaces an earlier one with
+    /// the same name, so /etc overrides vendor units and explicit entries override both.
+    fn load_from<D: AsRef<Path>>(manifest_path: &Path, service_dirs: &[D]) -> Result<Self> {
+        let manifest = Manifest::read(manifest_path)?;
+        for key in Self::unknown_manifest_keys(manifest_path).unwrap_or_default() {
+            warn!(
+                "{}: ignoring unknown key `{}`",
+                manifest_path.display(),
+                key.join(".")
+            );
+        }
+
+        let mut services: Vec<ServiceConfig> = Vec::new();
+        let mut templates: Vec<ServiceConfig> = Vec::new();
+        for path in manifest.service_files(service_dirs)?.into_iter().flatten() {
+            // One broken file only costs its own service.
+            let (service, merged, _) = match Self::load_service_with_sources(&path, service_dirs) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    error!("Skipping service file {}: {:#}", path.display(), e);
+                    continue;
+                }
+            };
+            for key in ServiceConfig::unknown_keys(&Value::Mapping(merged)) {
+                warn!("{}: ignoring unknown key `{}`", path.display(), key);
+            }
+            let loaded = if template::is_template(&service.name) {
+                &mut templates
+            } else {
+                &mut services
+            };
+            match loaded.iter_mut().find(|s| s.name == service.name) {
+                Some(existing) => *existing = service,
+                None => loaded.push(service),
+            }
+        }
 
-        Ok(Self { services })
+        for name in &manifest.instances {
+            let Some((template_name, instance)) = template::split_instance(name) else {
+                bail!(
+                    "invalid instance name `{name}`: expected TEMPLATE@INSTANCE, the instance made of letters, digits and `:_.-`"
+                );
+            };
+            let Some(template) = templates.iter().find(|t| t.name == template_name) else {
+                bail!("no template `{template_name}` for instance `{name}`");
+            };
+            let service = template::instantiate(template, instance);
+            if !services.iter().any(|s| s.name == service.name) {
+                services.push(service);
+            }
+        }
+
+        for (name, target) in &manifest.targets {
+            if let Some(missing) = target
+                .requires
+                .iter()
+                .find(|required| !manifest.targets.contains_key(*required))
+            {
+                bail!("target `{name}` requires undefined target `{missing}`");
+            }
+        }
+        if let Some(name) = &manifest.default_target
+            && !manifest.targets.contains_key(name)
+        {
+            bail!("default target `{name}` is not defined");
+        }
+
+        let mut signal_table = SignalTable::new();
+        for (name, action) in manifest.signals {
+            if let SignalAction::Isolate(target) = &action
+                && !manifest.targets.contains_key(target)
+            {
+                bail!("signal `{name}` isolates undefined target `{target}`");
+            }
+            signal_table.insert(signals::parse(&name)?, action);
+        }
+
+        Ok(Self {
+            services,
+            templates,
+            targets: manifest.targets,
+            default_target: manifest.default_target,
+            signals: signal_table,
+        })
+    }
+
+    /// Service files referenced by the manifest, grouped by origin in load order: one
+    /// group per discovery directory, one for `include` globs and one for `services`.
+    /// Service names only have to be unique within a group.
+    ///
+    /// Without a manifest, discovery mode is used.
+    pub fn service_files<D: AsRef<Path>>(
+        manifest_path: &Path,
+        service_dirs: &[D],
+    ) -> Result<Vec<Vec<PathBuf>>> {
+        Manifest::read(manifest_path)?.service_files(service_dirs)
+    }
+
+    /// Fails if a service takes the name of the entrypoint, which would replace it.
+    pub fn reserve_entrypoint(&self) -> Result<()> {
+        if self.services.iter().any(|s| s.name == ENTRYPOINT_SERVICE) {
+            bail!(
+                "service `{ENTRYPOINT_SERVICE}` is reserved for the command given after `--`; rename it"
+            );
+        }
+        Ok(())
+    }
+
+    /// Keys of the manifest that sima does not know and ignores, as paths from the top.
+    /// A missing manifest has none.
+    pub fn unknown_manifest_keys(manifest_path: &Path) -> Result<Vec<Vec<String>>> {
+        match fs::read_to_string(manifest_path) {
+            Ok(source) => Ok(Manifest::unknown_keys(
+                &Format::of(manifest_path).parse(&source)?,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => {
+                Err(err).with_context(|| format!("failed to open {}", manifest_path.display()))
+            }
+        }
+    }
+
+    /// Top-level keys of the service file at `path` that are not fields of a service.
+    pub fn unknown_service_keys(path: &Path) -> Result<Vec<String>> {
+        let source = fs::read_to_string(path)?;
+        Ok(ServiceConfig::unknown_keys(
+            &Format::of(path).parse(&source)?,
+        ))
+    }
+
+    /// Targets defined in the manifest and the default target.
+    pub fn targets(manifest_path: &Path) -> Result<(Targets, Option<String>)> {
+        let manifest = Manifest::read(manifest_path)?;
+        Ok((manifest.targets, manifest.default_target))
+    }
+
+    /// File systems to mount early in boot: the manifest's `early_mounts`, or the API
+    /// file systems if it has none.
+    pub fn early_mounts(manifest_path: &Path) -> Result<Vec<MountConfig>> {
+        Ok(Manifest::read(manifest_path)?
+            .early_mounts
+            .unwrap_or_else(mounts::default_early_mounts))
     }
 }
 
+/// Keys of the mapping `value` that are not fields of the type of `sample`, which
+/// serializes every field it has.
+fn unknown_keys<T: Serialize>(sample: &T, value: &Value) -> Vec<String> {
+    let (Ok(Value::Mapping(known)), Value::Mapping(value)) = (serde_yaml::to_value(sample), value)
+    else {
+        return Vec::new();
+    };
+    value
+        .keys()
+        .filter_map(Value::as_str)
+        .filter(|key| !known.contains_key(*key))
+        .map(String::from)
+        .collect()
+}
+
+fn scan_service_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", dir.display())),
+    };
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && format::is_config_file(&path) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn expand_include(pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = glob::glob(pattern)
+        .with_context(|| format!("invalid include pattern `{pattern}`"))?
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+    Ok(paths)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::ServiceConfig;
+    use super::{ProtectSystem, ServiceConfig, SimaConfig};
+    use crate::condition::Condition;
+    use crate::signals::SignalAction;
+    use crate::testutil::{scratch_dir, write_service};
+    use nix::sys::signal::Signal;
+    use std::fs;
+    use std::path::PathBuf;
 
     #[test]
     fn service_config_deserializes_environment_with_shell_escapes() {
@@ -71,4 +484,230 @@ environment:
             ])
         );
     }
+
+    #[test]
+    fn service_config_deserializes_sandbox_options() {
+        let yaml = r#"
+name: daemon
+cmdline: /usr/bin/daemon
+private_tmp: true
+read_only_paths: [/srv]
+inaccessible_paths: [/home]
+bind_paths: ['/var/lib/daemon:/data']
+protect_system: strict
+"#;
+
+        let config: ServiceConfig =
+            serde_yaml::from_str(yaml).expect("service config should parse");
+
+        assert!(config.private_tmp);
+        assert_eq!(config.read_only_paths, vec![PathBuf::from("/srv")]);
+        assert!(config.read_write_paths.is_empty());
+        assert_eq!(config.inaccessible_paths, vec![PathBuf::from("/home")]);
+        assert_eq!(config.bind_paths, vec!["/var/lib/daemon:/data".to_string()]);
+        assert_eq!(config.protect_system, Some(ProtectSystem::Strict));
+    }
+
+    #[test]
+    fn service_config_deserializes_conditions() {
+        let yaml = r#"
+name: gpu-daemon
+cmdline: /usr/bin/gpu-daemon
+conditions:
+  - path_exists: /dev/dri
+  - not:
+      virtualization: container
+assertions:
+  - architecture: aarch64
+"#;
+
+        let config: ServiceConfig =
+            serde_yaml::from_str(yaml).expect("service config should parse");
+
+        assert_eq!(
+            config.conditions,
+            vec![
+                Condition::PathExists("/dev/dri".to_string()),
+                Condition::Not(Box::new(Condition::Virtualization("container".to_string()))),
+            ]
+        );
+        assert_eq!(
+            config.assertions,
+            vec![Condition::Architecture("aarch64".to_string())]
+        );
+    }
+
+    #[test]
+    fn load_discovers_services_and_etc_overrides_vendor() {
+        let root = scratch_dir("discover");
+        let vendor = root.join("usr/lib/sima.d");
+        let etc = root.join("etc/sima.d");
+        write_service(&vendor, "a.yml", "a", "/usr/bin/vendor-a");
+        write_service(&vendor, "b.yml", "b", "/usr/bin/b");
+        write_service(&etc, "a-local.yml", "a", "/usr/local/bin/a");
+        write_service(&etc, "notes.txt", "ignored", "/bin/false");
+
+        let config = SimaConfig::load_from(&root.join("missing.yml"), &[&vendor, &etc])
+            .expect("config should load");
+
+        let services: Vec<_> = config
+            .services
+            .iter()
+            .map(|s| (s.name.as_str(), s.cmdline.as_str()))
+            .collect();
+        assert_eq!(
+            services,
+            vec![("a", "/usr/local/bin/a"), ("b", "/usr/bin/b")]
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn load_expands_include_globs() {
+        let root = scratch_dir("include");
+        let dir = root.join("sima.d");
+        write_service(&dir, "one.yml", "one", "/bin/one");
+        write_service(&dir, "two.yml", "two", "/bin/two");
+        let manifest = root.join("sima.yml");
+        fs::write(
+            &manifest,
+            format!("include:\n  - {}/*.yml\n", dir.display()),
+        )
+        .expect("manifest should be written");
+
+        let config =
+            SimaConfig::load_from(&manifest, &[root.join("unused")]).expect("config should load");
+
+        let names: Vec<_> = config.services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["one", "two"]);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn load_separates_templates_and_instantiates_manifest_instances() {
+        let root = scratch_dir("template");
+        let dir = root.join("sima.d");
+        write_service(&dir, "getty@.yml", "getty@", "/sbin/agetty %i");
+        let manifest = root.join("sima.yml");
+        fs::write(
+            &manifest,
+            format!(
+                "include:\n  - {}/*.yml\ninstances:\n  - getty@tty1\n",
+                dir.display()
+            ),
+        )
+        .expect("manifest should be written");
+
+        let config =
+            SimaConfig::load_from(&manifest, &[root.join("unused")]).expect("config should load");
+
+        let services: Vec<_> = config
+            .services
+            .iter()
+            .map(|s| (s.name.as_str(), s.cmdline.as_str()))
+            .collect();
+        assert_eq!(services, vec![("getty@tty1", "/sbin/agetty tty1")]);
+        assert_eq!(config.templates.len(), 1);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn early_mounts_default_to_api_file_systems() {
+        let root = scratch_dir("early-mounts");
+        let manifest = root.join("sima.yml");
+        fs::write(&manifest, "discover: true\n").expect("manifest should be written");
+        let defaults = SimaConfig::early_mounts(&manifest).expect("manifest should parse");
+        assert_eq!(defaults.first().map(|m| m.fstype.as_str()), Some("proc"));
+
+        fs::write(
+            &manifest,
+            "early_mounts:\n  - source: proc\n    target: /proc\n    fstype: proc\n",
+        )
+        .expect("manifest should be written");
+        let mounts = SimaConfig::early_mounts(&manifest).expect("manifest should parse");
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].options, "");
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn load_parses_the_signal_table() {
+        let root = scratch_dir("signals");
+        let manifest = root.join("sima.yml");
+        fs::write(
+            &manifest,
+            "targets:\n  maint: {}\nsignals:\n  SIGUSR2:\n    isolate: maint\n  SIGRTMIN+2: poweroff\n",
+        )
+        .expect("manifest should be written");
+        let config =
+            SimaConfig::load_from(&manifest, &[root.join("unused")]).expect("config should load");
+        assert_eq!(
+            config.signals.get(&(Signal::SIGUSR2 as i32)),
+            Some(&SignalAction::Isolate("maint".to_string()))
+        );
+        assert_eq!(config.signals.len(), 2);
+
+        fs::write(&manifest, "signals:\n  SIGUSR2:\n    isolate: nowhere\n")
+            .expect("manifest should be written");
+        assert!(SimaConfig::load_from(&manifest, &[root.join("unused")]).is_err());
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn load_ignores_unknown_keys_and_skips_broken_service_files() {
+        let root = scratch_dir("lenient");
+        let dir = root.join("sima.d");
+        fs::create_dir_all(&dir).expect("service dir should be created");
+        fs::write(
+            dir.join("a.yml"),
+            "name: a\ncmdline: /bin/a\nrestart: always\n",
+        )
+        .expect("service should be written");
+        fs::write(dir.join("b.yml"), "name: b\ncmdline: [\n").expect("service should be written");
+        let manifest = root.join("sima.yml");
+        fs::write(
+            &manifest,
+            "discover: true\nwatchdog: 30\ntargets:\n  rescue:\n    wants: [a]\n",
+        )
+        .expect("manifest should be written");
+
+        let config = SimaConfig::load_from(&manifest, &[&dir]).expect("config should load");
+        let names: Vec<_> = config.services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["a"]);
+        assert_eq!(
+            SimaConfig::unknown_manifest_keys(&manifest).expect("manifest should parse"),
+            vec![
+                vec!["watchdog".to_string()],
+                vec![
+                    "targets".to_string(),
+                    "rescue".to_string(),
+                    "wants".to_string()
+                ],
+            ]
+        );
+        assert_eq!(
+            SimaConfig::unknown_service_keys(&dir.join("a.yml")).expect("service should parse"),
+            vec!["restart".to_string()]
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn entrypoint_name_is_reserved() {
+        let root = scratch_dir("entrypoint");
+        write_service(&root, "main.yml", "main", "/bin/app");
+        let manifest = root.join("sima.yml");
+        fs::write(
+            &manifest,
+            format!("services: ['{}']\n", root.join("main.yml").display()),
+        )
+        .expect("manifest should be written");
+
+        let config = SimaConfig::load(&manifest).expect("config should load");
+        let err = config
+            .reserve_entrypoint()
+            .expect_err("a service named main should be rejected");
+        assert!(err.to_string().contains("reserved"));
+        let _ = fs::remove_dir_all(root);
+    }
 }
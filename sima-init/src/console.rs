@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use nix::libc;
+use nix::unistd::{dup2_stderr, dup2_stdin, dup2_stdout};
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const CONSOLE: &str = "/dev/console";
+
+/// Whether progress lines are printed: only once PID 1 has attached to the console.
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Opens the console without making it our controlling terminal, so that typing Ctrl-C
+/// there does not send SIGINT to init.
+pub fn open() -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(CONSOLE)
+        .with_context(|| format!("failed to open {CONSOLE}"))
+}
+
+/// Makes the console sima's stdin, stdout and stderr, which services inherit too. The
+/// kernel only sets these up if /dev/console existed before init started, and an
+/// initramfs may have left them pointing elsewhere.
+pub fn attach_stdio() -> Result<()> {
+    let console = open()?;
+    dup2_stdin(&console).context("failed to redirect stdin")?;
+    dup2_stdout(&console).context("failed to redirect stdout")?;
+    dup2_stderr(&console).context("failed to redirect stderr")?;
+    PROGRESS.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Prints `[  OK  ] message` on the console. Progress lines do not go to the log file,
+/// which records the same events as log messages.
+pub fn ok(message: &str) {
+    print_status("  OK  ", "\x1b[0;32m", message);
+}
+
+/// Prints `[FAILED] message` on the console.
+pub fn failed(message: &str) {
+    print_status("FAILED", "\x1b[0;1;31m", message);
+}
+
+fn print_status(status: &str, color: &str, message: &str) {
+    if !PROGRESS.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut stdout = std::io::stdout().lock();
+    let _ = if stdout.is_terminal() {
+        writeln!(stdout, "[{color}{status}\x1b[0m] {message}")
+    } else {
+        writeln!(stdout, "[{status}] {message}")
+    };
+}
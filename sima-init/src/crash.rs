@@ -0,0 +1,78 @@
+use crate::boot;
+use crate::options::Options;
+use crate::reexec;
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::Pid;
+use spdlog::{error, info};
+use std::backtrace::Backtrace;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Duration;
+
+/// What init does after sima-init panics, since exiting would panic the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashAction {
+    /// Stop managing services and only reap orphaned processes.
+    Freeze,
+    /// Enter emergency mode; continuing restarts sima-init.
+    Emergency,
+}
+
+impl std::str::FromStr for CrashAction {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "freeze" => Ok(CrashAction::Freeze),
+            "emergency" => Ok(CrashAction::Emergency),
+            _ => Err(format!("unknown crash action `{action}`")),
+        }
+    }
+}
+
+/// Logs panics with a backtrace, to the console and the log file alike. Unwinding goes
+/// on afterwards so that [`handle`] can take over in `main`.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        error!(
+            "sima-init panicked in thread '{}': {}\n{}",
+            thread.name().unwrap_or("<unnamed>"),
+            info,
+            Backtrace::force_capture()
+        );
+        spdlog::default_logger().flush();
+    }));
+}
+
+/// Applies the configured crash action after a panic reached `main`. Never returns.
+pub fn handle(options: &Options) -> ! {
+    match options.crash_action {
+        CrashAction::Freeze => freeze(),
+        CrashAction::Emergency => {
+            boot::emergency(&options.emergency_shell, "sima-init crashed");
+            restart()
+        }
+    }
+}
+
+fn freeze() -> ! {
+    error!("Freezing: services are no longer managed, orphaned processes are still reaped");
+    loop {
+        if waitpid(Pid::from_raw(-1), None).is_err() {
+            // No children right now.
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+/// Kills every other process, whose state sima-init has lost, and runs sima-init afresh.
+fn restart() -> ! {
+    info!("Restarting sima-init");
+    let _ = signal::kill(Pid::from_raw(-1), Signal::SIGKILL);
+    let exe = std::env::current_exe().unwrap_or_else(|_| "/sbin/sima-init".into());
+    let err = Command::new(&exe).args(reexec::fresh_arguments()).exec();
+    error!("Failed to restart {}: {}", exe.display(), err);
+    freeze()
+}
@@ -0,0 +1,214 @@
+use crate::format::{self, Format};
+use anyhow::{Context, Result, bail};
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Drop-ins for service `foo` live in `foo.yml.d/` inside any service directory.
+const DROPIN_SUFFIX: &str = ".yml.d";
+const RESET_TAG: &str = "reset";
+
+/// Context attached to errors in a drop-in fragment, so they can be reported against it.
+#[derive(Debug)]
+pub struct FragmentError(pub PathBuf);
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to load drop-in {}", self.0.display())
+    }
+}
+
+/// The files that contributed to each top-level key of a merged definition.
+pub type Sources = Vec<(String, Vec<PathBuf>)>;
+
+/// Drop-in fragments for service `name`, ordered by file name. A fragment in a later
+/// directory replaces one with the same file name in an earlier directory.
+pub fn find_dropins<D: AsRef<Path>>(name: &str, dirs: &[D]) -> Result<Vec<PathBuf>> {
+    let mut fragments = BTreeMap::new();
+
+    for dir in dirs {
+        let dropin_dir = dir.as_ref().join(format!("{name}{DROPIN_SUFFIX}"));
+        let entries = match fs::read_dir(&dropin_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read {}", dropin_dir.display()));
+            }
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_file()
+                && format::is_config_file(&path)
+                && let Some(file_name) = path.file_name()
+            {
+                fragments.insert(file_name.to_os_string(), path);
+            }
+        }
+    }
+
+    Ok(fragments.into_values().collect())
+}
+
+/// Records `origin` as the source of every key in `base`.
+pub fn initial_sources(base: &Mapping, origin: &Path) -> Sources {
+    base.keys()
+        .filter_map(Value::as_str)
+        .map(|key| (key.to_string(), vec![origin.to_path_buf()]))
+        .collect()
+}
+
+/// Reads the fragment at `path` and merges it into `base`.
+pub fn apply(base: &mut Mapping, path: &Path, sources: &mut Sources) -> Result<()> {
+    let text = fs::read_to_string(path)?;
+    if let Some(fragment) = Format::of(path).parse::<Option<Mapping>>(&text)? {
+        merge(base, fragment, path, sources)?;
+    }
+    Ok(())
+}
+
+/// Merges a drop-in fragment into `base`: scalars and mappings replace the previous
+/// value, lists are appended, and a value tagged `!reset` replaces the previous value
+/// outright (`key: !reset null` returns the key to its default).
+pub fn merge(
+    base: &mut Mapping,
+    fragment: Mapping,
+    origin: &Path,
+    sources: &mut Sources,
+) -> Result<()> {
+    for (key, value) in fragment {
+        let Some(name) = key.as_str().map(str::to_string) else {
+            bail!("non-string key in {}", origin.display());
+        };
+        if name == "name" && base.get(&key) != Some(&value) {
+            bail!("{}: drop-ins cannot rename a service", origin.display());
+        }
+
+        let append = match (base.get_mut(&key), value) {
+            (_, Value::Tagged(tagged)) if tagged.tag == RESET_TAG => {
+                if tagged.value.is_null() {
+                    base.remove(&key);
+                    sources.retain(|(k, _)| *k != name);
+                    continue;
+                }
+                base.insert(key, tagged.value);
+                false
+            }
+            (Some(Value::Sequence(existing)), Value::Sequence(items)) => {
+                existing.extend(items);
+                true
+            }
+            (_, value) => {
+                base.insert(key, value);
+                false
+            }
+        };
+
+        match sources.iter_mut().find(|(k, _)| *k == name) {
+            Some((_, files)) if append => files.push(origin.to_path_buf()),
+            Some((_, files)) => *files = vec![origin.to_path_buf()],
+            None => sources.push((name, vec![origin.to_path_buf()])),
+        }
+    }
+    Ok(())
+}
+
+/// Renders a merged definition as YAML, with a comment naming the source of each key.
+pub fn render(merged: &Mapping, sources: &Sources) -> Result<String> {
+    let mut out = String::new();
+    for (key, value) in merged {
+        let name = key.as_str().unwrap_or_default();
+        if let Some((_, files)) = sources.iter().find(|(k, _)| k == name) {
+            let files: Vec<_> = files.iter().map(|f| f.display().to_string()).collect();
+            out.push_str(&format!("# {}\n", files.join(", ")));
+        }
+        let mut entry = Mapping::new();
+        entry.insert(key.clone(), value.clone());
+        out.push_str(&serde_yaml::to_string(&entry)?);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{initial_sources, merge};
+    use serde_yaml::Mapping;
+    use std::path::{Path, PathBuf};
+
+    fn mapping(yaml: &str) -> Mapping {
+        serde_yaml::from_str(yaml).expect("mapping should parse")
+    }
+
+    #[test]
+    fn merge_overrides_scalars_and_appends_lists() {
+        let base_path = Path::new("/usr/lib/sima.d/app.yml");
+        let dropin = Path::new("/etc/sima.d/app.yml.d/local.yml");
+        let mut base = mapping("name: app\ncmdline: /bin/app\nenvironment: [A=1]\n");
+        let mut sources = initial_sources(&base, base_path);
+
+        merge(
+            &mut base,
+            mapping("cmdline: /bin/app --debug\nenvironment: [B=2]\n"),
+            dropin,
+            &mut sources,
+        )
+        .expect("merge should succeed");
+
+        assert_eq!(
+            base,
+            mapping("name: app\ncmdline: /bin/app --debug\nenvironment: [A=1, B=2]\n")
+        );
+        assert_eq!(
+            sources,
+            vec![
+                ("name".to_string(), vec![PathBuf::from(base_path)]),
+                ("cmdline".to_string(), vec![PathBuf::from(dropin)]),
+                (
+                    "environment".to_string(),
+                    vec![PathBuf::from(base_path), PathBuf::from(dropin)]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_reset_replaces_or_clears_values() {
+        let dropin = Path::new("/etc/sima.d/app.yml.d/reset.yml");
+        let mut base =
+            mapping("name: app\ncmdline: /bin/app\nenvironment: [A=1]\nprivate_tmp: true\n");
+        let mut sources = initial_sources(&base, Path::new("/etc/sima.d/app.yml"));
+
+        merge(
+            &mut base,
+            mapping("environment: !reset [B=2]\nprivate_tmp: !reset null\n"),
+            dropin,
+            &mut sources,
+        )
+        .expect("merge should succeed");
+
+        assert_eq!(
+            base,
+            mapping("name: app\ncmdline: /bin/app\nenvironment: [B=2]\n")
+        );
+        assert!(!sources.iter().any(|(key, _)| key == "private_tmp"));
+    }
+
+    #[test]
+    fn merge_rejects_renaming() {
+        let mut base = mapping("name: app\ncmdline: /bin/app\n");
+        let mut sources = Vec::new();
+
+        let err = merge(
+            &mut base,
+            mapping("name: other\n"),
+            Path::new("/etc/sima.d/app.yml.d/x.yml"),
+            &mut sources,
+        )
+        .expect_err("merge should fail");
+        assert!(err.to_string().contains("cannot rename"));
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sima_proto::Enablement;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "enablement.yml";
+
+/// Administrator overrides of service enablement, persisted across reboots.
+///
+/// A service that is neither enabled nor disabled here follows its `autostart` setting.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EnablementStore {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    enabled: BTreeSet<String>,
+    #[serde(default)]
+    disabled: BTreeSet<String>,
+    #[serde(default)]
+    masked: BTreeSet<String>,
+    /// Masked for this boot only, from `sima.mask=` on the kernel command line.
+    #[serde(skip)]
+    runtime_masked: BTreeSet<String>,
+}
+
+impl EnablementStore {
+    /// An empty store that will be saved to `state_dir`.
+    pub fn empty(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(STATE_FILE),
+            ..Default::default()
+        }
+    }
+
+    /// Loads the store from `state_dir`; a missing file yields an empty store.
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(STATE_FILE);
+        let mut store: Self = match fs::read_to_string(&path) {
+            Ok(text) => serde_yaml::from_str::<Option<Self>>(&text)
+                .with_context(|| format!("failed to parse {}", path.display()))?
+                .unwrap_or_default(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()));
+            }
+        };
+        store.path = path;
+        Ok(store)
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        let tmp = self.path.with_extension("yml.tmp");
+        fs::write(&tmp, serde_yaml::to_string(self)?)
+            .with_context(|| format!("failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+
+    /// Applies `change` only once the result is saved, so that a failed save leaves
+    /// the store as it was.
+    fn update(&mut self, change: impl FnOnce(&mut Self)) -> Result<()> {
+        let mut updated = self.clone();
+        change(&mut updated);
+        updated.save()?;
+        *self = updated;
+        Ok(())
+    }
+
+    pub fn state(&self, name: &str, autostart: bool) -> Enablement {
+        if self.is_masked(name) {
+            Enablement::Masked
+        } else if self.enabled.contains(name) {
+            Enablement::Enabled
+        } else if self.disabled.contains(name) {
+            Enablement::Disabled
+        } else if autostart {
+            Enablement::Enabled
+        } else {
+            Enablement::Disabled
+        }
+    }
+
+    pub fn is_masked(&self, name: &str) -> bool {
+        self.masked.contains(name) || self.runtime_masked.contains(name)
+    }
+
+    /// Services enabled explicitly, including template instances not in the manifest.
+    pub fn enabled(&self) -> impl Iterator<Item = &str> {
+        self.enabled.iter().map(String::as_str)
+    }
+
+    pub fn enable(&mut self, name: &str) -> Result<()> {
+        self.update(|store| {
+            store.disabled.remove(name);
+            store.enabled.insert(name.to_string());
+        })
+    }
+
+    pub fn disable(&mut self, name: &str) -> Result<()> {
+        self.update(|store| {
+            store.enabled.remove(name);
+            store.disabled.insert(name.to_string());
+        })
+    }
+
+    pub fn mask(&mut self, name: &str) -> Result<()> {
+        self.update(|store| {
+            store.masked.insert(name.to_string());
+        })
+    }
+
+    /// Masks `name` until the next boot, without saving it.
+    pub fn mask_runtime(&mut self, name: &str) {
+        self.runtime_masked.insert(name.to_string());
+    }
+
+    pub fn runtime_masked(&self) -> &BTreeSet<String> {
+        &self.runtime_masked
+    }
+
+    /// Replaces the runtime masks, e.g. with those of the sima-init that executed us.
+    pub fn set_runtime_masked(&mut self, names: BTreeSet<String>) {
+        self.runtime_masked = names;
+    }
+
+    pub fn unmask(&mut self, name: &str) -> Result<()> {
+        self.update(|store| {
+            store.runtime_masked.remove(name);
+            store.masked.remove(name);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EnablementStore;
+    use crate::testutil::scratch_dir;
+    use sima_proto::Enablement;
+    use std::fs;
+
+    #[test]
+    fn enablement_overrides_autostart_and_persists() {
+        let dir = scratch_dir("enablement");
+
+        let mut store = EnablementStore::load(&dir).expect("store should load");
+        assert_eq!(store.state("a", true), Enablement::Enabled);
+        assert_eq!(store.state("b", false), Enablement::Disabled);
+
+        store.disable("a").expect("disable should persist");
+        store.enable("b").expect("enable should persist");
+        store.mask("c").expect("mask should persist");
+        store.mask_runtime("d");
+        assert_eq!(store.state("d", true), Enablement::Masked);
+
+        let store = EnablementStore::load(&dir).expect("store should reload");
+        assert_eq!(store.state("a", true), Enablement::Disabled);
+        assert_eq!(store.state("b", false), Enablement::Enabled);
+        assert_eq!(store.state("c", true), Enablement::Masked);
+        assert_eq!(store.state("d", true), Enablement::Enabled);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn failed_save_leaves_the_store_unchanged() {
+        let dir = scratch_dir("enablement-readonly");
+        // The state directory cannot be created below a regular file.
+        let file = dir.join("file");
+        fs::write(&file, "").expect("file should be written");
+        let mut store = EnablementStore::empty(&file.join("state"));
+        store.mask_runtime("b");
+
+        assert!(store.enable("a").is_err());
+        assert!(store.mask("a").is_err());
+        assert!(store.unmask("b").is_err());
+        assert_eq!(store.state("a", false), Enablement::Disabled);
+        assert_eq!(store.state("b", true), Enablement::Masked);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
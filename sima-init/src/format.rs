@@ -0,0 +1,320 @@
+use anyhow::Result;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess,
+    Visitor,
+};
+use std::fmt;
+use std::path::Path;
+
+/// Extensions of configuration files, YAML first.
+pub const EXTENSIONS: [&str; 4] = ["yml", "yaml", "toml", "json"];
+
+/// The format of a configuration file, chosen by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Files without a recognized extension are read as YAML.
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+
+    /// Parses `source`. Errors keep the parser's own error type so that its location can
+    /// be recovered with [`location`].
+    pub fn parse<T: DeserializeOwned>(self, source: &str) -> Result<T> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::from_str(source)?,
+            Format::Toml => toml::from_str(source)?,
+            Format::Json => serde_json::from_str(source)?,
+        })
+    }
+}
+
+impl Format {
+    /// The 1-based line and column of the key at the end of `path`, e.g.
+    /// `["targets", "rescue"]`. Numbers in `path` index sequences.
+    pub fn locate_key(self, source: &str, path: &[&str]) -> Option<(usize, usize)> {
+        self.locate(source, Walk { path, key: true })
+    }
+
+    /// The 1-based line and column of the value at the end of `path`, e.g.
+    /// `["environment", "2"]`.
+    pub fn locate_value(self, source: &str, path: &[&str]) -> Option<(usize, usize)> {
+        self.locate(source, Walk { path, key: false })
+    }
+
+    /// Walks the document with the format's own parser until the node is reached, then
+    /// fails there so that the parser reports its position.
+    fn locate(self, source: &str, walk: Walk) -> Option<(usize, usize)> {
+        let err: anyhow::Error = match self {
+            Format::Yaml => walk
+                .deserialize(serde_yaml::Deserializer::from_str(source))
+                .err()?
+                .into(),
+            Format::Toml => walk
+                .deserialize(toml::de::Deserializer::parse(source).ok()?)
+                .err()?
+                .into(),
+            Format::Json => walk
+                .deserialize(&mut serde_json::Deserializer::from_str(source))
+                .err()?
+                .into(),
+        };
+        let (location, message) = location(&err, source)?;
+        message.contains(FOUND).then_some(location)
+    }
+}
+
+/// Error raised on reaching the node looked for by [`Format::locate`].
+const FOUND: &str = "sima: node found";
+
+fn found<E: de::Error>() -> E {
+    E::custom(FOUND)
+}
+
+/// Follows `path` through a document, failing at the node it leads to.
+#[derive(Clone, Copy)]
+struct Walk<'a> {
+    path: &'a [&'a str],
+    /// Stop at the last key of `path` rather than at its value.
+    key: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for Walk<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        if self.path.is_empty() {
+            deserializer.deserialize_any(Found)
+        } else {
+            deserializer.deserialize_any(self)
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for Walk<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a mapping or sequence")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let Some((&name, rest)) = self.path.split_first() else {
+            return Err(found());
+        };
+        let key = Key {
+            name,
+            stop: self.key && rest.is_empty(),
+        };
+        while let Some(matched) = map.next_key_seed(key)? {
+            if matched {
+                return map.next_value_seed(Walk { path: rest, ..self });
+            }
+            map.next_value::<IgnoredAny>()?;
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let Some((index, rest)) = self.path.split_first() else {
+            return Err(found());
+        };
+        let Ok(index) = index.parse::<usize>() else {
+            return Ok(());
+        };
+        for _ in 0..index {
+            if seq.next_element::<IgnoredAny>()?.is_none() {
+                return Ok(());
+            }
+        }
+        seq.next_element_seed(Walk { path: rest, ..self })?;
+        Ok(())
+    }
+}
+
+/// A mapping key; true if it is `name`. Fails on it if `stop` is set.
+#[derive(Clone, Copy)]
+struct Key<'a> {
+    name: &'a str,
+    stop: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for Key<'_> {
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Key<'_> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a key")
+    }
+
+    fn visit_str<E: de::Error>(self, key: &str) -> Result<bool, E> {
+        match key == self.name {
+            true if self.stop => Err(found()),
+            matched => Ok(matched),
+        }
+    }
+}
+
+/// Fails on whatever node it is given.
+struct Found;
+
+impl<'de> Visitor<'de> for Found {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any value")
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+        Err(found())
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<(), E> {
+        Err(found())
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<(), E> {
+        Err(found())
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<(), E> {
+        Err(found())
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+        Err(found())
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        Err(found())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, _: A) -> Result<(), A::Error> {
+        Err(found())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, _: A) -> Result<(), A::Error> {
+        Err(found())
+    }
+}
+
+/// Whether `path` has the extension of a configuration file.
+pub fn is_config_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.contains(&ext))
+}
+
+/// The 1-based line and column of a parse error in `source`, and its message without
+/// the location the parser appends to it.
+pub fn location(err: &anyhow::Error, source: &str) -> Option<((usize, usize), String)> {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<serde_yaml::Error>() {
+            let location = err.location()?;
+            return Some((
+                (location.line(), location.column()),
+                strip_location(&err.to_string(), " at line "),
+            ));
+        }
+        if let Some(err) = cause.downcast_ref::<serde_json::Error>() {
+            return Some((
+                (err.line(), err.column()),
+                strip_location(&err.to_string(), " at line "),
+            ));
+        }
+        if let Some(err) = cause.downcast_ref::<toml::de::Error>() {
+            let offset = err.span()?.start;
+            let before = source.get(..offset)?;
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            return Some(((line, column), err.message().to_string()));
+        }
+    }
+    None
+}
+
+fn strip_location(message: &str, marker: &str) -> String {
+    message
+        .rsplit_once(marker)
+        .map_or(message, |(message, _)| message)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, location};
+    use crate::config::ServiceConfig;
+    use std::path::Path;
+
+    #[test]
+    fn format_is_chosen_by_extension() {
+        assert_eq!(Format::of(Path::new("/etc/sima.d/a.toml")), Format::Toml);
+        assert_eq!(Format::of(Path::new("/etc/sima.d/a.json")), Format::Json);
+        assert_eq!(Format::of(Path::new("/etc/sima.d/a.yaml")), Format::Yaml);
+        assert_eq!(Format::of(Path::new("/etc/sima.d/a")), Format::Yaml);
+    }
+
+    #[test]
+    fn locate_finds_keys_and_values_with_the_parser() {
+        let yaml =
+            "# name: in a comment\nname: a\ncmdline: /bin/a\nenvironment:\n  - A=1\n  - name\n";
+        assert_eq!(Format::Yaml.locate_key(yaml, &["name"]), Some((2, 1)));
+        assert_eq!(Format::Yaml.locate_value(yaml, &["cmdline"]), Some((3, 10)));
+        assert_eq!(
+            Format::Yaml.locate_value(yaml, &["environment", "1"]),
+            Some((6, 5))
+        );
+        assert_eq!(Format::Yaml.locate_key(yaml, &["restart"]), None);
+
+        let toml = "name = \"a\"\n\n[targets.rescue]\nservices = [\"getty\"]\n";
+        assert_eq!(
+            Format::Toml
+                .locate_value(toml, &["targets", "rescue", "services", "0"])
+                .map(|(line, _)| line),
+            Some(4)
+        );
+
+        let json = "{\n  \"name\": \"a\",\n  \"cmdline\": \"/bin/a\"\n}";
+        assert_eq!(
+            Format::Json
+                .locate_value(json, &["cmdline"])
+                .map(|(line, _)| line),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn errors_report_format_specific_locations() {
+        let toml = "name = \"a\"\ncmdline = \"/bin/a\"\nautostart = \"yes\"\n";
+        let err = Format::Toml
+            .parse::<ServiceConfig>(toml)
+            .expect_err("wrong type should fail");
+        let (at, message) = location(&err, toml).expect("toml error should have a location");
+        assert_eq!(at.0, 3);
+        assert!(message.contains("invalid type: string \"yes\""));
+
+        let json = "{\n  \"name\": \"a\",\n  \"cmdline\": 42\n}";
+        let err = Format::Json
+            .parse::<ServiceConfig>(json)
+            .expect_err("wrong type should fail");
+        let (at, message) = location(&err, json).expect("json error should have a location");
+        assert_eq!(at.0, 3);
+        assert!(message.starts_with("invalid type: integer `42`"));
+    }
+}
@@ -1,10 +1,15 @@
-use anyhow::Result;
+use crate::reexec::SavedListener;
+use crate::sys;
+use anyhow::{Context, Result};
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
 use sima_proto::{
-    Request, Response, ServiceInfo, decode, encode, should_fallback_from_socket_error, socket_paths,
+    ConfigDiff, KexecImage, Request, Response, ScheduledShutdown, ServiceInfo, decode, encode,
+    should_fallback_from_socket_error, socket_paths,
 };
 use spdlog::{error, info, warn};
 use std::fs;
 use std::io;
+use std::os::fd::AsRawFd;
 use std::os::unix::fs::FileTypeExt;
 use std::os::unix::net::UnixStream as StdUnixStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -15,10 +20,25 @@ pub enum IpcCommand {
     Start(String),
     Stop(String),
     Restart(String),
-    Status(oneshot::Sender<Vec<ServiceInfo>>),
+    Status(oneshot::Sender<(Vec<ServiceInfo>, Option<ScheduledShutdown>)>),
     Poweroff,
-    Reboot,
+    /// Replies before shutting down, or with why it cannot.
+    Reboot(Option<String>, oneshot::Sender<Result<(), String>>),
     SoftReboot,
+    /// Replies with why it cannot execute the new binary, or with a sender to notify
+    /// once the reply has been written, as the connection does not survive exec.
+    DaemonReexec(oneshot::Sender<Result<oneshot::Sender<()>, String>>),
+    Halt,
+    Kexec(KexecImage, oneshot::Sender<Result<(), String>>),
+    ScheduleShutdown(ScheduledShutdown, oneshot::Sender<Result<(), String>>),
+    CancelShutdown(oneshot::Sender<Result<(), String>>),
+    ReloadConfig(oneshot::Sender<Result<ConfigDiff, String>>),
+    Cat(String, oneshot::Sender<Result<String, String>>),
+    Enable(String, oneshot::Sender<Result<(), String>>),
+    Disable(String, oneshot::Sender<Result<(), String>>),
+    Mask(String, oneshot::Sender<Result<(), String>>),
+    Unmask(String, oneshot::Sender<Result<(), String>>),
+    Isolate(String, oneshot::Sender<Result<(), String>>),
 }
 
 pub struct IpcServer {
@@ -27,8 +47,12 @@ pub struct IpcServer {
 }
 
 impl IpcServer {
-    pub fn new() -> Result<Self> {
-        let (listener, socket_path) = bind_listener(&socket_paths())?;
+    /// Binds `socket_path` if given, otherwise the default paths with fallback.
+    pub fn new(socket_path: Option<&str>) -> Result<Self> {
+        let (listener, socket_path) = match socket_path {
+            Some(path) => bind_listener(&[path])?,
+            None => bind_listener(&socket_paths())?,
+        };
         info!("IPC server listening on {}", socket_path);
         Ok(Self {
             listener,
@@ -36,6 +60,37 @@ impl IpcServer {
         })
     }
 
+    /// Takes over the socket the sima-init that executed us was listening on, with
+    /// the connections waiting on it.
+    pub fn adopt(saved: SavedListener) -> Result<Self> {
+        let listener = sys::adopt_listener(saved.fd)
+            .with_context(|| format!("failed to adopt the IPC socket (fd {})", saved.fd))?;
+        listener.set_nonblocking(true)?;
+        info!("IPC server still listening on {}", saved.path);
+        Ok(Self {
+            listener: UnixListener::from_std(listener)?,
+            socket_path: saved.path,
+        })
+    }
+
+    /// Leaves the socket open across exec, for the new sima-init to adopt.
+    pub fn hand_over(&self) -> Result<SavedListener> {
+        fcntl(&self.listener, FcntlArg::F_SETFD(FdFlag::empty()))
+            .context("failed to keep the IPC socket open across exec")?;
+        Ok(SavedListener {
+            fd: self.listener.as_raw_fd(),
+            path: self.socket_path.clone(),
+        })
+    }
+
+    /// Closes the socket on exec again, once a handover fell through, so that services
+    /// do not inherit it.
+    pub fn take_back(&self) {
+        if let Err(e) = fcntl(&self.listener, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            warn!("Failed to close the IPC socket on exec: {}", e);
+        }
+    }
+
     pub async fn accept(&self) -> Result<UnixStream> {
         let (stream, _) = self.listener.accept().await?;
         Ok(stream)
@@ -75,7 +130,7 @@ fn bind_listener_with<T>(
                     last_error = Some(err);
                     continue;
                 }
-                return Err(err.into());
+                return Err(err);
             }
         }
     }
@@ -145,10 +200,16 @@ pub async fn handle_client(
     };
 
     info!("IPC request: {:?}", req);
-    let resp = process_request(req, cmd_tx).await;
+    let (resp, written) = match req {
+        Request::DaemonReexec => daemon_reexec(cmd_tx).await,
+        req => (process_request(req, cmd_tx).await, None),
+    };
 
     let data = encode(&resp)?;
     stream.write_all(&data).await?;
+    if let Some(written) = written {
+        let _ = written.send(());
+    }
     Ok(())
 }
 
@@ -178,7 +239,7 @@ async fn process_request(req: Request, cmd_tx: &mpsc::Sender<IpcCommand>) -> Res
                 return Response::Error("Internal error".into());
             }
             match rx.await {
-                Ok(statuses) => Response::StatusReport(statuses),
+                Ok((statuses, shutdown)) => Response::StatusReport(statuses, shutdown),
                 Err(_) => Response::Error("Failed to get status".into()),
             }
         }
@@ -188,25 +249,241 @@ async fn process_request(req: Request, cmd_tx: &mpsc::Sender<IpcCommand>) -> Res
             }
             Response::Ok
         }
-        Request::Reboot => {
-            if cmd_tx.send(IpcCommand::Reboot).await.is_err() {
+        Request::Reboot { arg } => {
+            send_and_wait(cmd_tx, |tx| IpcCommand::Reboot(arg, tx), "Failed to reboot").await
+        }
+        Request::Halt => {
+            if cmd_tx.send(IpcCommand::Halt).await.is_err() {
                 return Response::Error("Internal error".into());
             }
             Response::Ok
         }
+        Request::ScheduleShutdown(shutdown) => {
+            send_and_wait(
+                cmd_tx,
+                |tx| IpcCommand::ScheduleShutdown(shutdown, tx),
+                "Failed to schedule the shutdown",
+            )
+            .await
+        }
+        Request::CancelShutdown => {
+            send_and_wait(
+                cmd_tx,
+                IpcCommand::CancelShutdown,
+                "Failed to cancel the shutdown",
+            )
+            .await
+        }
+        Request::Kexec(image) => {
+            send_and_wait(cmd_tx, |tx| IpcCommand::Kexec(image, tx), "Failed to kexec").await
+        }
         Request::SoftReboot => {
             if cmd_tx.send(IpcCommand::SoftReboot).await.is_err() {
                 return Response::Error("Internal error".into());
             }
             Response::Ok
         }
+        Request::DaemonReexec => daemon_reexec(cmd_tx).await.0,
+        Request::ReloadConfig => {
+            let (tx, rx) = oneshot::channel();
+            if cmd_tx.send(IpcCommand::ReloadConfig(tx)).await.is_err() {
+                return Response::Error("Internal error".into());
+            }
+            match rx.await {
+                Ok(Ok(diff)) => Response::ConfigReloaded(diff),
+                Ok(Err(e)) => Response::Error(e),
+                Err(_) => Response::Error("Failed to reload config".into()),
+            }
+        }
+        Request::Cat(name) => {
+            let (tx, rx) = oneshot::channel();
+            if cmd_tx.send(IpcCommand::Cat(name, tx)).await.is_err() {
+                return Response::Error("Internal error".into());
+            }
+            match rx.await {
+                Ok(Ok(definition)) => Response::Definition(definition),
+                Ok(Err(e)) => Response::Error(e),
+                Err(_) => Response::Error("Failed to read service definition".into()),
+            }
+        }
+        Request::Enable(name) => {
+            send_and_wait(cmd_tx, |tx| IpcCommand::Enable(name, tx), ENABLEMENT_FAILED).await
+        }
+        Request::Disable(name) => {
+            send_and_wait(
+                cmd_tx,
+                |tx| IpcCommand::Disable(name, tx),
+                ENABLEMENT_FAILED,
+            )
+            .await
+        }
+        Request::Mask(name) => {
+            send_and_wait(cmd_tx, |tx| IpcCommand::Mask(name, tx), ENABLEMENT_FAILED).await
+        }
+        Request::Unmask(name) => {
+            send_and_wait(cmd_tx, |tx| IpcCommand::Unmask(name, tx), ENABLEMENT_FAILED).await
+        }
+        Request::Isolate(target) => {
+            send_and_wait(
+                cmd_tx,
+                |tx| IpcCommand::Isolate(target, tx),
+                "Failed to isolate target",
+            )
+            .await
+        }
+    }
+}
+
+const ENABLEMENT_FAILED: &str = "Failed to update service enablement";
+
+/// The reply to `daemon-reexec`, and whom to tell once it is written.
+async fn daemon_reexec(
+    cmd_tx: &mpsc::Sender<IpcCommand>,
+) -> (Response, Option<oneshot::Sender<()>>) {
+    let (tx, rx) = oneshot::channel();
+    if cmd_tx.send(IpcCommand::DaemonReexec(tx)).await.is_err() {
+        return (Response::Error("Internal error".into()), None);
+    }
+    match rx.await {
+        Ok(Ok(written)) => (Response::Ok, Some(written)),
+        Ok(Err(e)) => (Response::Error(e), None),
+        Err(_) => (Response::Error("Failed to re-execute".into()), None),
+    }
+}
+
+/// Sends a command that reports success or an error message, and waits for the reply.
+async fn send_and_wait(
+    cmd_tx: &mpsc::Sender<IpcCommand>,
+    command: impl FnOnce(oneshot::Sender<Result<(), String>>) -> IpcCommand,
+    failure: &str,
+) -> Response {
+    let (tx, rx) = oneshot::channel();
+    if cmd_tx.send(command(tx)).await.is_err() {
+        return Response::Error("Internal error".into());
+    }
+    match rx.await {
+        Ok(Ok(())) => Response::Ok,
+        Ok(Err(e)) => Response::Error(e),
+        Err(_) => Response::Error(failure.into()),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::bind_listener_with;
+    use super::{IpcCommand, IpcServer, bind_listener_with, process_request};
+    use crate::reexec::SavedListener;
+    use crate::testutil::scratch_dir;
+    use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+    use nix::unistd::dup;
+    use sima_proto::{KexecImage, Request, Response};
+    use std::fs::{self, File};
     use std::io;
+    use std::os::fd::IntoRawFd;
+    use std::path::PathBuf;
+    use tokio::net::UnixStream;
+    use tokio::sync::mpsc;
+
+    fn closes_on_exec(server: &IpcServer) -> bool {
+        let flags = fcntl(&server.listener, FcntlArg::F_GETFD).expect("flags should be read");
+        FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC)
+    }
+
+    #[tokio::test]
+    async fn handed_over_socket_keeps_accepting_connections() {
+        let root = scratch_dir("ipc-handover");
+        let path = root.join("sima.sock");
+        let server = IpcServer::new(path.to_str()).expect("the socket should be bound");
+        assert!(closes_on_exec(&server));
+        let saved = server
+            .hand_over()
+            .expect("the socket should be handed over");
+        assert!(!closes_on_exec(&server));
+        server.take_back();
+        assert!(closes_on_exec(&server));
+
+        // The old process keeps the socket file; the new one gets a descriptor that
+        // this runtime has not registered yet.
+        let saved = SavedListener {
+            fd: dup(&server.listener)
+                .expect("the socket should be duplicated")
+                .into_raw_fd(),
+            ..saved
+        };
+        std::mem::forget(server);
+        let client = UnixStream::connect(&path)
+            .await
+            .expect("client should connect");
+        let server = IpcServer::adopt(saved).expect("the socket should be adopted");
+        assert!(closes_on_exec(&server));
+        server
+            .accept()
+            .await
+            .expect("the waiting client should be accepted");
+        drop(client);
+        drop(server);
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn adopt_refuses_a_descriptor_that_is_not_a_socket() {
+        let file = File::open("/proc/self/stat").expect("file should open");
+        let saved = SavedListener {
+            fd: file.into_raw_fd(),
+            path: "/run/sima.sock".to_string(),
+        };
+        assert!(IpcServer::adopt(saved).is_err());
+    }
+
+    #[tokio::test]
+    async fn halt_is_handed_to_the_service_manager() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(1);
+        let response = process_request(Request::Halt, &cmd_tx).await;
+        assert!(matches!(response, Response::Ok));
+        assert!(matches!(cmd_rx.recv().await, Some(IpcCommand::Halt)));
+    }
+
+    #[tokio::test]
+    async fn reboot_hands_its_argument_to_the_service_manager() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(1);
+        let manager = tokio::spawn(async move {
+            let Some(IpcCommand::Reboot(arg, tx)) = cmd_rx.recv().await else {
+                panic!("expected a reboot command");
+            };
+            let _ = tx.send(Ok(()));
+            arg
+        });
+
+        let request = Request::Reboot {
+            arg: Some("bootloader".into()),
+        };
+        let response = process_request(request, &cmd_tx).await;
+        assert!(matches!(response, Response::Ok));
+        assert_eq!(
+            manager.await.expect("manager should reply").as_deref(),
+            Some("bootloader")
+        );
+    }
+
+    #[tokio::test]
+    async fn kexec_reports_why_the_kernel_was_not_loaded() {
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(1);
+        let manager = tokio::spawn(async move {
+            let Some(IpcCommand::Kexec(image, tx)) = cmd_rx.recv().await else {
+                panic!("expected a kexec command");
+            };
+            let _ = tx.send(Err("no kernel".to_string()));
+            image
+        });
+        let image = KexecImage {
+            kernel: Some(PathBuf::from("/boot/vmlinuz")),
+            ..Default::default()
+        };
+
+        let response = process_request(Request::Kexec(image.clone()), &cmd_tx).await;
+        assert!(matches!(response, Response::Error(e) if e == "no kernel"));
+        assert_eq!(manager.await.expect("manager should reply"), image);
+    }
 
     #[test]
     fn bind_listener_falls_back_on_permission_denied() {
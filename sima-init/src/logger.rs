@@ -1,22 +1,26 @@
 use anyhow::Result;
-use spdlog::sink::{RotatingFileSink, RotationPolicy};
+use spdlog::sink::{RotatingFileSink, RotationPolicy, Sink};
 use spdlog::{Level, LevelFilter, Logger, LoggerBuilder};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+
+/// Sinks of the logger spdlog starts with, kept so that initializing again replaces the
+/// file sink instead of adding a second one.
+static CONSOLE_SINKS: OnceLock<Vec<Arc<dyn Sink>>> = OnceLock::new();
+
+fn console_sinks() -> Vec<Arc<dyn Sink>> {
+    CONSOLE_SINKS
+        .get_or_init(|| spdlog::default_logger().sinks().to_owned())
+        .clone()
+}
 
 pub struct Log;
 
 impl Log {
-    pub fn init(logdir: Option<PathBuf>, verbose: bool) -> Result<()> {
+    pub fn init(logdir: Option<PathBuf>, level: Level) -> Result<()> {
         let mut logger: LoggerBuilder = Logger::builder();
-        logger.sinks(spdlog::default_logger().sinks().to_owned());
-
-        let level = if verbose {
-            LevelFilter::MoreSevereEqual(Level::Debug)
-        } else {
-            LevelFilter::MoreSevereEqual(Level::Info)
-        };
-        logger.level_filter(level);
+        logger.sinks(console_sinks());
+        logger.level_filter(LevelFilter::MoreSevereEqual(level));
 
         if let Some(logdir) = &logdir {
             let logdir = PathBuf::from(logdir);
@@ -49,4 +53,18 @@ impl Log {
 
         Ok(())
     }
+
+    /// Closes the log file, keeping the level, so that its file system can be unmounted
+    /// or remounted read-only. Messages still reach the console.
+    pub fn close_file() -> Result<()> {
+        let level = spdlog::default_logger().level_filter();
+        let logger = Logger::builder()
+            .sinks(console_sinks())
+            .level_filter(level)
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to build logger: {e}"))?;
+        spdlog::default_logger().flush();
+        let _ = spdlog::swap_default_logger(Arc::new(logger));
+        Ok(())
+    }
 }
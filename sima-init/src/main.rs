@@ -1,18 +1,43 @@
-#![forbid(unsafe_code)]
+#![deny(unsafe_code)]
 #![forbid(clippy::unwrap_used)]
 
+mod boot;
+mod check;
+mod cmdline;
+mod condition;
 mod config;
+mod console;
+mod crash;
+mod dropin;
+mod enablement;
+mod format;
 mod ipc;
 mod logger;
+mod mounts;
+mod options;
+mod reexec;
+mod sandbox;
+mod schedule;
 mod service;
+mod shutdown;
+mod signals;
+mod sys;
+mod target;
+mod template;
+#[cfg(test)]
+mod testutil;
 
 use crate::config::SimaConfig;
 use crate::logger::Log;
+use crate::mounts::MountTable;
+use crate::options::Options;
 use crate::service::ServiceManager;
 use figlet_rs::FIGfont;
+use nix::sys::prctl::set_child_subreaper;
 use platform_info::{PlatformInfo, PlatformInfoAPI, UNameAPI};
-use spdlog::{error as fatal, info};
-use std::path::PathBuf;
+use spdlog::{error as fatal, info, warn};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 
 fn sysinfo_test() -> PlatformInfo {
     let pid = std::process::id();
@@ -29,29 +54,169 @@ fn sysinfo_test() -> PlatformInfo {
     PlatformInfo::new().expect("Unable to get platform info")
 }
 
-#[tokio::main]
-async fn main() {
-    let logdir = PathBuf::from("/var/log/sima");
-    Log::init(Some(logdir), true).unwrap_or_else(|e| {
-        eprintln!("ERROR: Failed to initialize logger: {e}");
-        std::process::exit(-1);
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some(sandbox::SANDBOX_EXEC_ARG) {
+        sandbox::exec(&args[2..]);
+    }
+
+    let options = Options::parse();
+    if options.check_config {
+        std::process::exit(check::run(&options.config));
+    }
+
+    crash::install_panic_hook();
+    // Panics anywhere below, including in the service manager's task, unwind to here.
+    if panic::catch_unwind(AssertUnwindSafe(|| run(&options))).is_err() {
+        if options.is_init() {
+            crash::handle(&options);
+        }
+        std::process::exit(101);
+    }
+}
+
+fn run(options: &Options) {
+    tokio::runtime::Runtime::new()
+        .expect("Unable to start the tokio runtime")
+        .block_on(boot(options));
+}
+
+async fn boot(options: &Options) {
+    let entrypoint = !options.command.is_empty();
+    let init = options.is_init();
+    // Executed again by `simactl daemon-reexec`: the system is up and the services are
+    // running, only the service manager has to pick up where it left off.
+    let reexec = options.deserialize.is_some();
+    let mut saved = options.deserialize.and_then(|fd| {
+        reexec::load(fd)
+            .map_err(|e| {
+                eprintln!(
+                    "ERROR: Failed to restore the saved state, restarting the services: {e:#}"
+                );
+                // Nothing tracks the services still running; they would run twice.
+                reexec::stop_children();
+            })
+            .ok()
     });
+    if !init && let Err(e) = options.secure_user_dir() {
+        eprintln!("ERROR: {e:#}");
+        std::process::exit(1);
+    }
+    let mut mount_table = MountTable::default();
+    if init && std::process::id() == 1 && reexec {
+        if let Err(e) = console::attach_stdio() {
+            eprintln!("WARNING: Keeping the inherited stdio: {e:#}");
+        }
+        mount_table = MountTable::load(Path::new(mounts::FSTAB_PATH)).unwrap_or_else(|e| {
+            eprintln!("WARNING: Not managing fstab entries: {e:#}");
+            MountTable::default()
+        });
+    } else if init && std::process::id() == 1 {
+        // The log directory, the IPC socket and service sandboxes live on these.
+        let early_mounts = SimaConfig::early_mounts(&options.config).unwrap_or_else(|e| {
+            eprintln!("WARNING: Using the default early mounts: {e:#}");
+            mounts::default_early_mounts()
+        });
+        mounts::mount_early(&early_mounts);
+        if let Err(e) = console::attach_stdio() {
+            eprintln!("WARNING: Keeping the inherited stdio: {e:#}");
+        }
+        if options.emergency {
+            boot::console_shell(
+                &options.emergency_shell,
+                "Emergency mode requested on the kernel command line",
+            );
+        }
+
+        mount_table = MountTable::load(Path::new(mounts::FSTAB_PATH)).unwrap_or_else(|e| {
+            eprintln!("WARNING: Not mounting fstab entries: {e:#}");
+            MountTable::default()
+        });
+        mount_table.mount_all();
+    }
+    Log::init(Some(options.log_dir.clone()), options.log_level)
+        .or_else(|e| {
+            // Container images often have no writable log directory, and init cannot give
+            // up over it; the console will do.
+            if options.supervisor && !entrypoint {
+                return Err(e);
+            }
+            eprintln!("WARNING: Logging to the console only: {e}");
+            Log::init(None, options.log_level)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("ERROR: Failed to initialize logger: {e}");
+            std::process::exit(-1);
+        });
 
     info!(
         "System Init & Management Agent v{}",
         env!("CARGO_PKG_VERSION")
     );
-    let sys_info = sysinfo_test();
-    info!("Machine info: {}", sys_info.machine().display());
+    if !init {
+        // Orphaned descendants of services are reparented to us instead of to init.
+        if let Err(e) = set_child_subreaper(true) {
+            warn!("Failed to become a child subreaper: {}", e);
+        }
+        info!("Running as supervisor (PID {})", std::process::id());
+    } else if reexec {
+        info!("Re-executed as PID {}", std::process::id());
+    } else {
+        let sys_info = sysinfo_test();
+        info!("Machine info: {}", sys_info.machine().display());
+        boot::setup();
+        if options.break_shell {
+            boot::console_shell(
+                &options.emergency_shell,
+                "Stopped before starting services (sima.break)",
+            );
+        }
+    }
 
-    let config = SimaConfig::load().unwrap_or_else(|e| {
-        eprintln!("ERROR: Failed to load config: {e}");
-        std::process::exit(-1);
-    });
+    loop {
+        let config = SimaConfig::load(&options.config).and_then(|config| {
+            if entrypoint {
+                config.reserve_entrypoint()?;
+            }
+            Ok(config)
+        });
+        let config = match config {
+            Ok(config) => config,
+            Err(e) if init => {
+                fatal!("Failed to load config: {:#}", e);
+                boot::emergency(
+                    &options.emergency_shell,
+                    &format!("failed to load {}: {e:#}", options.config.display()),
+                );
+                continue;
+            }
+            Err(e) => {
+                eprintln!("ERROR: Failed to load config: {e}");
+                std::process::exit(-1);
+            }
+        };
 
-    let mut manager = ServiceManager::new(config);
-    if let Err(e) = manager.run().await {
-        fatal!("Fatal: ServiceManager crashed: {}", e);
-        std::process::exit(-1);
+        let mut manager = ServiceManager::new(config, options, mount_table.clone());
+        if let Some(saved) = saved.take() {
+            manager.restore(saved);
+        }
+        // On its own task, a panic in the manager reaches us as an error.
+        let result = match tokio::spawn(async move { manager.run().await }).await {
+            Ok(result) => result,
+            Err(e) => panic::resume_unwind(e.into_panic()),
+        };
+        match result {
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                fatal!("Fatal: ServiceManager crashed: {:#}", e);
+                if !init {
+                    std::process::exit(-1);
+                }
+                boot::emergency(
+                    &options.emergency_shell,
+                    &format!("the service manager crashed: {e:#}"),
+                );
+            }
+        }
     }
 }
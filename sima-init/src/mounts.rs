@@ -0,0 +1,583 @@
+use crate::console;
+use crate::sandbox::{mount_points_below, unescape_mountinfo};
+use anyhow::{Context, Result, anyhow};
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags, mount, umount, umount2};
+use nix::unistd::sync;
+use serde::{Deserialize, Serialize};
+use spdlog::{error, info, warn};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const FSTAB_PATH: &str = "/etc/fstab";
+
+/// A file system to mount, as in the `early_mounts` list of the manifest.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MountConfig {
+    pub source: String,
+    pub target: PathBuf,
+    pub fstype: String,
+    /// Comma-separated mount options, e.g. `nosuid,nodev,mode=0755`.
+    #[serde(default)]
+    pub options: String,
+}
+
+impl MountConfig {
+    fn new(source: &str, target: &str, fstype: &str, options: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            target: PathBuf::from(target),
+            fstype: fstype.to_string(),
+            options: options.to_string(),
+        }
+    }
+}
+
+/// The API file systems every Linux userspace expects, in mount order.
+pub fn default_early_mounts() -> Vec<MountConfig> {
+    vec![
+        MountConfig::new("proc", "/proc", "proc", "nosuid,nodev,noexec"),
+        MountConfig::new("sysfs", "/sys", "sysfs", "nosuid,nodev,noexec"),
+        MountConfig::new("devtmpfs", "/dev", "devtmpfs", "nosuid,mode=0755"),
+        MountConfig::new(
+            "devpts",
+            "/dev/pts",
+            "devpts",
+            "nosuid,noexec,mode=0620,gid=5,ptmxmode=0666",
+        ),
+        MountConfig::new("tmpfs", "/dev/shm", "tmpfs", "nosuid,nodev,mode=1777"),
+        MountConfig::new("tmpfs", "/run", "tmpfs", "nosuid,nodev,mode=0755"),
+        MountConfig::new(
+            "cgroup2",
+            "/sys/fs/cgroup",
+            "cgroup2",
+            "nosuid,nodev,noexec",
+        ),
+    ]
+}
+
+/// Mounts each of `mounts` that is not mounted yet. Failures are logged and skipped so
+/// that boot can go on with whatever is available.
+pub fn mount_early(mounts: &[MountConfig]) {
+    for entry in mounts {
+        if is_mount_point(&entry.target) {
+            info!("{} is already mounted", entry.target.display());
+            continue;
+        }
+        match mount_entry(entry) {
+            Ok(()) => info!(
+                "Mounted {} ({}) on {}",
+                entry.source,
+                entry.fstype,
+                entry.target.display()
+            ),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+}
+
+fn mount_entry(entry: &MountConfig) -> Result<()> {
+    let (flags, data) = parse_options(&entry.options);
+    // Mount points below /dev and /sys only exist once their parent is mounted.
+    fs::create_dir_all(&entry.target)
+        .with_context(|| format!("failed to create {}", entry.target.display()))?;
+    let fstypes = if entry.fstype == "auto" {
+        block_fstypes()
+    } else {
+        vec![entry.fstype.clone()]
+    };
+    let mut result = Err(Errno::ENODEV);
+    for fstype in &fstypes {
+        result = mount(
+            Some(entry.source.as_str()),
+            &entry.target,
+            Some(fstype.as_str()),
+            flags,
+            (!data.is_empty()).then_some(data.as_str()),
+        );
+        if result.is_ok() {
+            break;
+        }
+    }
+    result.with_context(|| {
+        format!(
+            "failed to mount {} ({}) on {}",
+            entry.source,
+            entry.fstype,
+            entry.target.display()
+        )
+    })
+}
+
+/// Splits mount options into the flags the kernel takes separately and the data string
+/// passed to the file system.
+pub fn parse_options(options: &str) -> (MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        match option {
+            "defaults" | "rw" => {}
+            // Read by mount(8) and sima rather than the kernel.
+            "auto" | "noauto" | "nofail" | "user" | "nouser" | "users" | "owner" | "_netdev" => {}
+            option if option.starts_with("x-") => {}
+            "ro" => flags |= MsFlags::MS_RDONLY,
+            "nosuid" => flags |= MsFlags::MS_NOSUID,
+            "nodev" => flags |= MsFlags::MS_NODEV,
+            "noexec" => flags |= MsFlags::MS_NOEXEC,
+            "sync" => flags |= MsFlags::MS_SYNCHRONOUS,
+            "dirsync" => flags |= MsFlags::MS_DIRSYNC,
+            "noatime" => flags |= MsFlags::MS_NOATIME,
+            "nodiratime" => flags |= MsFlags::MS_NODIRATIME,
+            "relatime" => flags |= MsFlags::MS_RELATIME,
+            "strictatime" => flags |= MsFlags::MS_STRICTATIME,
+            "bind" => flags |= MsFlags::MS_BIND,
+            "rbind" => flags |= MsFlags::MS_BIND | MsFlags::MS_REC,
+            option => data.push(option),
+        }
+    }
+    (flags, data.join(","))
+}
+
+/// File systems the kernel can mount from a block device, tried in turn for `auto`.
+fn block_fstypes() -> Vec<String> {
+    fs::read_to_string("/proc/filesystems")
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.starts_with("nodev"))
+        .map(|line| line.trim().to_string())
+        .filter(|fstype| !fstype.is_empty())
+        .collect()
+}
+
+/// An entry of `/etc/fstab`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FstabEntry {
+    pub mount: MountConfig,
+    /// Mounted at boot; cleared by `noauto`.
+    pub auto: bool,
+    /// `nofail`: failing to mount is only a warning.
+    pub nofail: bool,
+    /// Mount points from `x-sima.requires=` that are mounted first.
+    pub requires: Vec<PathBuf>,
+}
+
+impl FstabEntry {
+    /// Whether `other` has to be mounted before this entry: it is mounted above this
+    /// one, holds the source of this bind mount, or is required explicitly.
+    fn depends_on(&self, other: &FstabEntry) -> bool {
+        let target = &self.mount.target;
+        let bind = self
+            .mount
+            .options
+            .split(',')
+            .any(|o| o == "bind" || o == "rbind");
+        (target != &other.mount.target && target.starts_with(&other.mount.target))
+            || (bind && Path::new(&self.mount.source).starts_with(&other.mount.target))
+            || self.requires.contains(&other.mount.target)
+    }
+}
+
+/// Parses fstab(5). Swap entries are left out; malformed lines are logged and skipped.
+pub fn parse_fstab(source: &str) -> Vec<FstabEntry> {
+    let mut entries = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<String> = line.split_whitespace().map(unescape_mountinfo).collect();
+        let [spec, file, vfstype, rest @ ..] = fields.as_slice() else {
+            warn!(
+                "fstab line {}: expected at least 3 fields, ignoring it",
+                number + 1
+            );
+            continue;
+        };
+        if vfstype == "swap" || file == "none" {
+            continue;
+        }
+
+        let options = rest.first().map_or("defaults", String::as_str);
+        let mut entry = FstabEntry {
+            mount: MountConfig {
+                source: resolve_source(spec),
+                target: PathBuf::from(file),
+                fstype: vfstype.clone(),
+                options: options.to_string(),
+            },
+            auto: true,
+            nofail: false,
+            requires: Vec::new(),
+        };
+        for option in options.split(',') {
+            match option {
+                "noauto" => entry.auto = false,
+                "nofail" => entry.nofail = true,
+                option => {
+                    if let Some(path) = option.strip_prefix("x-sima.requires=") {
+                        entry.requires.push(PathBuf::from(path));
+                    }
+                }
+            }
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Turns `UUID=`, `LABEL=`, `PARTUUID=` and `PARTLABEL=` into the device links kept by
+/// udev or mdev under `/dev/disk`.
+fn resolve_source(spec: &str) -> String {
+    const TAGS: [(&str, &str); 4] = [
+        ("UUID=", "by-uuid"),
+        ("LABEL=", "by-label"),
+        ("PARTUUID=", "by-partuuid"),
+        ("PARTLABEL=", "by-partlabel"),
+    ];
+    TAGS.iter()
+        .find_map(|(tag, dir)| {
+            spec.strip_prefix(tag)
+                .map(|value| format!("/dev/disk/{dir}/{}", value.trim_matches('"')))
+        })
+        .unwrap_or_else(|| spec.to_string())
+}
+
+/// Sorts entries so that each comes after those it depends on, otherwise keeping the
+/// fstab order.
+fn order(mut pending: Vec<FstabEntry>) -> Vec<FstabEntry> {
+    let mut ordered = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let ready = (0..pending.len()).find(|&i| {
+            !pending
+                .iter()
+                .enumerate()
+                .any(|(j, other)| i != j && pending[i].depends_on(other))
+        });
+        match ready {
+            Some(index) => ordered.push(pending.remove(index)),
+            None => {
+                warn!(
+                    "Dependencies between fstab entries form a cycle, mounting the rest in file order"
+                );
+                ordered.append(&mut pending);
+            }
+        }
+    }
+    ordered
+}
+
+/// An fstab entry managed as a pseudo-service named after its mount point.
+#[derive(Debug, Clone)]
+pub struct MountUnit {
+    pub entry: FstabEntry,
+    /// Why the last attempt to mount failed.
+    pub failed: Option<String>,
+}
+
+impl MountUnit {
+    pub fn name(&self) -> String {
+        self.entry.mount.target.display().to_string()
+    }
+}
+
+/// The entries of `/etc/fstab` in mount order.
+#[derive(Debug, Clone, Default)]
+pub struct MountTable {
+    units: Vec<MountUnit>,
+}
+
+impl MountTable {
+    /// Reads `path`; a missing fstab is an empty table.
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()));
+            }
+        };
+        Ok(Self {
+            units: order(parse_fstab(&source))
+                .into_iter()
+                .map(|entry| MountUnit {
+                    entry,
+                    failed: None,
+                })
+                .collect(),
+        })
+    }
+
+    pub fn units(&self) -> impl Iterator<Item = &MountUnit> {
+        self.units.iter()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.units
+            .iter()
+            .position(|unit| unit.entry.mount.target == Path::new(name))
+    }
+
+    /// Records why the entry for mount point `name` failed to mount, as carried over by
+    /// `simactl daemon-reexec`.
+    pub fn set_failed(&mut self, name: &str, failed: String) {
+        if let Some(index) = self.find(name) {
+            self.units[index].failed = Some(failed);
+        }
+    }
+
+    /// Mounts every entry without `noauto`.
+    pub fn mount_all(&mut self) {
+        let mut attempted = HashSet::new();
+        for index in 0..self.units.len() {
+            if self.units[index].entry.auto {
+                let _ = self.mount_with_dependencies(index, &mut attempted);
+            }
+        }
+    }
+
+    /// Mounts the entry for mount point `name` and the entries it depends on.
+    pub fn start(&mut self, name: &str) -> Result<()> {
+        let index = self
+            .find(name)
+            .ok_or_else(|| anyhow!("{name} is not in {FSTAB_PATH}"))?;
+        self.mount_with_dependencies(index, &mut HashSet::new())
+    }
+
+    pub fn stop(&mut self, name: &str) -> Result<()> {
+        umount(name).with_context(|| format!("failed to unmount {name}"))?;
+        info!("Unmounted {}", name);
+        Ok(())
+    }
+
+    /// Mounts the entries at or above each of `paths` that are not mounted yet.
+    pub fn require(&mut self, paths: &[PathBuf]) -> Result<()> {
+        for path in paths {
+            for index in 0..self.units.len() {
+                let target = self.units[index].entry.mount.target.clone();
+                if path.starts_with(&target) && !is_mount_point(&target) {
+                    self.mount_with_dependencies(index, &mut HashSet::new())
+                        .with_context(|| format!("required mount {} failed", target.display()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `attempted` keeps an entry that several others depend on from being retried, and
+    /// breaks cycles.
+    fn mount_with_dependencies(
+        &mut self,
+        index: usize,
+        attempted: &mut HashSet<usize>,
+    ) -> Result<()> {
+        if !attempted.insert(index) {
+            return match &self.units[index].failed {
+                Some(reason) => Err(anyhow!("{reason}")),
+                None => Ok(()),
+            };
+        }
+
+        let dependencies: Vec<usize> = (0..self.units.len())
+            .filter(|&other| {
+                other != index && self.units[index].entry.depends_on(&self.units[other].entry)
+            })
+            .collect();
+        for dependency in dependencies {
+            if let Err(e) = self.mount_with_dependencies(dependency, attempted) {
+                let name = self.units[dependency].name();
+                return self.record(index, Err(e.context(format!("requires {name}"))));
+            }
+        }
+
+        let mount = &self.units[index].entry.mount;
+        let result = if !is_mount_point(&mount.target) {
+            mount_entry(mount).map(|()| {
+                info!(
+                    "Mounted {} ({}) on {}",
+                    mount.source,
+                    mount.fstype,
+                    mount.target.display()
+                )
+            })
+        } else if mount.target == Path::new("/") {
+            // The root file system is usually mounted read-only by the kernel or initramfs.
+            remount(mount).map(|()| info!("Remounted / ({})", mount.options))
+        } else {
+            info!("{} is already mounted", mount.target.display());
+            Ok(())
+        };
+        self.record(index, result)
+    }
+
+    fn record(&mut self, index: usize, result: Result<()>) -> Result<()> {
+        let unit = &mut self.units[index];
+        match &result {
+            Ok(()) => {
+                console::ok(&format!("Mounted {}", unit.name()));
+                unit.failed = None;
+            }
+            Err(e) => {
+                console::failed(&format!("Failed to mount {}", unit.name()));
+                if unit.entry.nofail {
+                    warn!("Failed to mount {}: {:#}", unit.name(), e);
+                } else {
+                    error!("Failed to mount {}: {:#}", unit.name(), e);
+                }
+                unit.failed = Some(format!("{e:#}"));
+            }
+        }
+        result
+    }
+}
+
+fn remount(entry: &MountConfig) -> Result<()> {
+    let (flags, data) = parse_options(&entry.options);
+    mount(
+        None::<&str>,
+        &entry.target,
+        None::<&str>,
+        flags | MsFlags::MS_REMOUNT,
+        (!data.is_empty()).then_some(data.as_str()),
+    )
+    .with_context(|| format!("failed to remount {}", entry.target.display()))
+}
+
+/// Prepares file systems for `reboot(2)`: unmounts everything except the root and API
+/// file systems in reverse mount order, remounts what is left read-only, detaches it
+/// lazily and syncs.
+pub fn unmount_all() {
+    // A mount can be busy until one mounted after it is gone, so keep going while a pass
+    // unmounts something.
+    let mut busy = Vec::new();
+    loop {
+        let targets: Vec<PathBuf> = mounted_targets()
+            .into_iter()
+            .rev()
+            .filter(|target| !is_api_mount(target))
+            .collect();
+        let attempted = targets.len();
+        busy.clear();
+        for target in targets {
+            match umount(&target) {
+                Ok(()) => info!("Unmounted {}", target.display()),
+                Err(e) => busy.push((target, e)),
+            }
+        }
+        if busy.is_empty() || busy.len() == attempted {
+            break;
+        }
+    }
+
+    for (target, e) in &busy {
+        warn!("Failed to unmount {}: {}", target.display(), e);
+    }
+    let mut busy: Vec<PathBuf> = busy.into_iter().map(|(target, _)| target).collect();
+    busy.dedup();
+    for target in busy.iter().chain([&PathBuf::from("/")]) {
+        let result = mount(
+            None::<&str>,
+            target,
+            None::<&str>,
+            MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            None::<&str>,
+        );
+        match result {
+            Ok(()) => info!("Remounted {} read-only", target.display()),
+            Err(e) => warn!("Failed to remount {} read-only: {}", target.display(), e),
+        }
+    }
+    // Detached mounts are cleaned up by the kernel once their last user is gone, which
+    // with everything killed is usually right away.
+    for target in &busy {
+        match umount2(target, MntFlags::MNT_DETACH) {
+            Ok(()) => info!("Detached {}", target.display()),
+            Err(e) => warn!("Failed to detach {}: {}", target.display(), e),
+        }
+    }
+    sync();
+}
+
+fn is_api_mount(target: &Path) -> bool {
+    target == Path::new("/")
+        || ["/proc", "/sys", "/dev", "/run"]
+            .iter()
+            .any(|api| target.starts_with(api))
+}
+
+/// Mount points from `/proc/self/mountinfo`, in the order they were mounted.
+pub fn mounted_targets() -> Vec<PathBuf> {
+    fs::read_to_string("/proc/self/mountinfo")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|target| PathBuf::from(unescape_mountinfo(target)))
+        .collect()
+}
+
+/// Whether `path` is the target of a mount, according to `/proc/self/mountinfo`.
+pub fn is_mount_point(path: &Path) -> bool {
+    let (Ok(path), Ok(mountinfo)) = (
+        fs::canonicalize(path),
+        fs::read_to_string("/proc/self/mountinfo"),
+    ) else {
+        return false;
+    };
+    mount_points_below(&mountinfo, &path)
+        .iter()
+        .any(|(target, _)| *target == path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{order, parse_fstab, parse_options};
+    use nix::mount::MsFlags;
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_options_separates_flags_from_data() {
+        let (flags, data) = parse_options("defaults,nosuid,noexec,mode=0620,gid=5,ro");
+
+        assert_eq!(
+            flags,
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC | MsFlags::MS_RDONLY
+        );
+        assert_eq!(data, "mode=0620,gid=5");
+    }
+
+    #[test]
+    fn parse_fstab_reads_options_and_orders_dependencies() {
+        let fstab = r#"
+# <file system> <mount point> <type> <options> <dump> <pass>
+/srv/data        /var/www/data  none  bind,x-sima.requires=/srv  0 0
+UUID=1234-abcd   /var          ext4  defaults   0 2
+/dev/sda3        /srv          xfs   nofail     0 2
+LABEL=my\040usb  /mnt/usb\040key vfat  noauto,user 0 0
+/dev/sda4        none          swap  sw         0 0
+/dev/sda1        /             ext4  rw,noatime 0 1
+"#;
+        let entries = parse_fstab(fstab);
+
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[1].mount.source, "/dev/disk/by-uuid/1234-abcd");
+        assert!(entries[2].nofail);
+        assert_eq!(entries[3].mount.source, "/dev/disk/by-label/my usb");
+        assert_eq!(entries[3].mount.target, PathBuf::from("/mnt/usb key"));
+        assert!(!entries[3].auto);
+        assert_eq!(entries[0].requires, vec![PathBuf::from("/srv")]);
+
+        let targets: Vec<_> = order(entries)
+            .into_iter()
+            .map(|entry| entry.mount.target.display().to_string())
+            .collect();
+        assert_eq!(
+            targets,
+            vec!["/", "/var", "/srv", "/var/www/data", "/mnt/usb key"]
+        );
+    }
+}
@@ -0,0 +1,412 @@
+use crate::cmdline::KernelCmdline;
+use crate::config::MANIFEST_PATH;
+use crate::crash::CrashAction;
+use anyhow::{Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use nix::unistd::getuid;
+use spdlog::Level;
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_LOG_DIR: &str = "/var/log/sima";
+const DEFAULT_STATE_DIR: &str = "/var/lib/sima";
+/// Target booted by `single` on the kernel command line.
+pub const RESCUE_TARGET: &str = "rescue";
+const DEFAULT_EMERGENCY_SHELL: &str = "/bin/sh";
+
+/// Built with the clap builder API: the derive macros emit lint attributes that clash
+/// with `forbid(clippy::unwrap_used)`.
+fn command() -> Command {
+    Command::new("sima-init")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("System Init & Management Agent")
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .value_parser(value_parser!(PathBuf))
+                .help("Path of the system manifest [default: /etc/sima.yml]"),
+        )
+        .arg(
+            Arg::new("log-dir")
+                .long("log-dir")
+                .value_name("DIR")
+                .value_parser(value_parser!(PathBuf))
+                .help("Directory for log files [default: /var/log/sima]"),
+        )
+        .arg(
+            Arg::new("state-dir")
+                .long("state-dir")
+                .value_name("DIR")
+                .value_parser(value_parser!(PathBuf))
+                .help("Directory for persistent state [default: /var/lib/sima]"),
+        )
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .value_name("PATH")
+                .help("IPC socket path [default: /run/sima.sock, falling back to /tmp/sima.sock]"),
+        )
+        .arg(
+            Arg::new("target")
+                .long("target")
+                .value_name("NAME")
+                .help("Target to start instead of the manifest's default_target"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::SetTrue)
+                .help("Log debug messages, as --log-level debug does"),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .value_parser(["critical", "error", "warn", "info", "debug", "trace"])
+                .help("Least severe level that is logged [default: info]"),
+        )
+        .arg(
+            Arg::new("emergency-shell")
+                .long("emergency-shell")
+                .value_name("PATH")
+                .value_parser(value_parser!(PathBuf))
+                .help("Shell for emergency mode, e.g. /sbin/sulogin [default: /bin/sh]"),
+        )
+        .arg(
+            Arg::new("crash-action")
+                .long("crash-action")
+                .value_name("ACTION")
+                .value_parser(["freeze", "emergency"])
+                .help("What to do if sima-init crashes as init [default: freeze]"),
+        )
+        .arg(
+            Arg::new("supervisor")
+                .long("supervisor")
+                .action(ArgAction::SetTrue)
+                .help("Run as a process supervisor instead of as PID 1"),
+        )
+        .arg(
+            Arg::new("check-config")
+                .long("check-config")
+                .action(ArgAction::SetTrue)
+                .help("Validate the configuration and exit"),
+        )
+        .arg(
+            // Set by `simactl daemon-reexec` on the new sima-init.
+            Arg::new("deserialize")
+                .long("deserialize")
+                .value_name("FD")
+                .value_parser(value_parser!(i32))
+                .hide(true),
+        )
+        .arg(
+            // Words the kernel passes through from its command line.
+            Arg::new("kernel-args").num_args(0..).hide(true),
+        )
+        .arg(
+            Arg::new("command")
+                .value_name("COMMAND")
+                .num_args(1..)
+                .last(true)
+                .help("Run COMMAND as the main service and exit with its status (container entrypoint)"),
+        )
+}
+
+/// Startup options, taken from the command line first, then from `sima.*` kernel
+/// command line parameters, then from the built-in defaults.
+#[derive(Debug)]
+pub struct Options {
+    pub config: PathBuf,
+    pub log_dir: PathBuf,
+    pub state_dir: PathBuf,
+    pub socket: Option<String>,
+    pub target: Option<String>,
+    pub log_level: Level,
+    pub check_config: bool,
+    pub supervisor: bool,
+    /// Main command given after `--`; sima exits when it does.
+    pub command: Vec<String>,
+    /// Services masked for this boot only (`sima.mask=`).
+    pub masked: Vec<String>,
+    /// Services started at boot in addition to the target (`sima.wants=`).
+    pub wants: Vec<String>,
+    /// Open a shell on the console before starting services (`sima.break`).
+    pub break_shell: bool,
+    /// Open a shell on the console before mounting anything but the API file systems
+    /// (`emergency`).
+    pub emergency: bool,
+    /// Run on the console in emergency mode and for `sima.break`.
+    pub emergency_shell: PathBuf,
+    pub crash_action: CrashAction,
+    /// File descriptor holding the state of the sima-init that executed us.
+    pub deserialize: Option<RawFd>,
+}
+
+impl Options {
+    pub fn parse() -> Self {
+        let matches = command().try_get_matches().unwrap_or_else(|e| {
+            // Exiting would panic the kernel when running as PID 1.
+            if std::process::id() != 1 {
+                e.exit();
+            }
+            eprintln!("WARNING: ignoring invalid arguments: {e}");
+            command().get_matches_from(["sima-init"])
+        });
+        // A supervisor or container entrypoint is not the system's init; the kernel
+        // command line is not meant for it.
+        let cmdline = if matches.get_flag("supervisor") || matches.contains_id("command") {
+            KernelCmdline::default()
+        } else {
+            KernelCmdline::load()
+        };
+        Self::resolve(&matches, &cmdline)
+    }
+
+    /// Options from `args` alone, without the kernel command line.
+    #[cfg(test)]
+    pub fn from_args(args: &[&str]) -> Self {
+        Self::resolve(&command().get_matches_from(args), &KernelCmdline::default())
+    }
+
+    /// Creates or checks the private directory under /tmp before a supervisor without
+    /// `$XDG_RUNTIME_DIR` or a home directory puts its socket, logs or state there.
+    pub fn secure_user_dir(&self) -> Result<()> {
+        let uid = getuid().as_raw();
+        let dir = sima_proto::user_fallback_dir(uid);
+        let used = self
+            .socket
+            .as_deref()
+            .is_some_and(|socket| Path::new(socket).starts_with(&dir))
+            || self.log_dir.starts_with(&dir)
+            || self.state_dir.starts_with(&dir);
+        if used {
+            sima_proto::ensure_private_dir(&dir, uid)
+                .with_context(|| format!("refusing to use {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Running as the system's init, where exiting would panic the kernel.
+    pub fn is_init(&self) -> bool {
+        !self.supervisor && self.command.is_empty()
+    }
+
+    fn resolve(matches: &ArgMatches, cmdline: &KernelCmdline) -> Self {
+        let supervisor = matches.get_flag("supervisor");
+        // The kernel passes words it does not know to init both as arguments and in
+        // /proc/cmdline; either is enough.
+        let kernel_args: Vec<&String> = matches
+            .get_many::<String>("kernel-args")
+            .into_iter()
+            .flatten()
+            .collect();
+        let kernel_arg =
+            |word: &str| cmdline.contains(word) || kernel_args.iter().any(|arg| *arg == word);
+        let single = ["single", "s", "S", "1"].into_iter().any(kernel_arg);
+        let (default_log_dir, default_state_dir) = if supervisor {
+            let dir = user_state_dir();
+            (dir.join("log"), dir)
+        } else {
+            (
+                PathBuf::from(DEFAULT_LOG_DIR),
+                PathBuf::from(DEFAULT_STATE_DIR),
+            )
+        };
+
+        Self {
+            config: matches
+                .get_one::<PathBuf>("config")
+                .cloned()
+                .or_else(|| cmdline.get("sima.config").map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from(MANIFEST_PATH)),
+            log_dir: matches
+                .get_one::<PathBuf>("log-dir")
+                .cloned()
+                .or_else(|| cmdline.get("sima.log_dir").map(PathBuf::from))
+                .unwrap_or(default_log_dir),
+            state_dir: matches
+                .get_one::<PathBuf>("state-dir")
+                .cloned()
+                .or_else(|| cmdline.get("sima.state_dir").map(PathBuf::from))
+                .unwrap_or(default_state_dir),
+            socket: matches
+                .get_one::<String>("socket")
+                .cloned()
+                .or_else(|| cmdline.get("sima.socket").map(String::from))
+                .or_else(|| supervisor.then(|| sima_proto::user_socket_path(getuid().as_raw()))),
+            target: matches
+                .get_one::<String>("target")
+                .cloned()
+                .or_else(|| single.then(|| RESCUE_TARGET.to_string()))
+                .or_else(|| cmdline.get("sima.target").map(String::from)),
+            log_level: log_level(matches, cmdline),
+            check_config: matches.get_flag("check-config"),
+            supervisor,
+            command: matches
+                .get_many::<String>("command")
+                .map(|words| words.cloned().collect())
+                .unwrap_or_default(),
+            masked: cmdline.values("sima.mask").map(String::from).collect(),
+            wants: cmdline.values("sima.wants").map(String::from).collect(),
+            break_shell: cmdline.flag("sima.break"),
+            emergency: kernel_arg("emergency"),
+            emergency_shell: matches
+                .get_one::<PathBuf>("emergency-shell")
+                .cloned()
+                .or_else(|| cmdline.get("sima.emergency_shell").map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_EMERGENCY_SHELL)),
+            crash_action: crash_action(matches, cmdline),
+            deserialize: matches.get_one::<RawFd>("deserialize").copied(),
+        }
+    }
+}
+
+/// `--crash-action`, then `sima.crash_action=`; freezing is the default.
+fn crash_action(matches: &ArgMatches, cmdline: &KernelCmdline) -> CrashAction {
+    let from_cmdline = cmdline.get("sima.crash_action").and_then(|action| {
+        action
+            .parse()
+            .map_err(|e| eprintln!("WARNING: ignoring sima.crash_action: {e}"))
+            .ok()
+    });
+    matches
+        .get_one::<String>("crash-action")
+        .and_then(|action| action.parse().ok())
+        .or(from_cmdline)
+        .unwrap_or(CrashAction::Freeze)
+}
+
+/// `--log-level`, `-v`, `sima.log_level=`, then `sima.verbose`. An unknown level on the
+/// kernel command line is reported and ignored.
+fn log_level(matches: &ArgMatches, cmdline: &KernelCmdline) -> Level {
+    if let Some(level) = matches
+        .get_one::<String>("log-level")
+        .and_then(|level| level.parse().ok())
+    {
+        return level;
+    }
+    if matches.get_flag("verbose") {
+        return Level::Debug;
+    }
+    if let Some(level) = cmdline.get("sima.log_level") {
+        match level.parse() {
+            Ok(level) => return level,
+            Err(_) => eprintln!("WARNING: ignoring unknown log level sima.log_level={level}"),
+        }
+    }
+    if cmdline.flag("sima.verbose") {
+        return Level::Debug;
+    }
+    Level::Info
+}
+
+/// Where a supervisor keeps its logs and state: `$XDG_STATE_HOME/sima`, by default
+/// `~/.local/state/sima`.
+fn user_state_dir() -> PathBuf {
+    let non_empty = |var| std::env::var_os(var).filter(|value| !value.is_empty());
+    non_empty("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| non_empty("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .unwrap_or_else(|| sima_proto::user_fallback_dir(getuid().as_raw()))
+        .join("sima")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Options, command};
+    use crate::cmdline::KernelCmdline;
+    use crate::crash::CrashAction;
+    use spdlog::Level;
+    use std::path::PathBuf;
+
+    #[test]
+    fn options_prefer_arguments_over_kernel_cmdline() {
+        let matches =
+            command().get_matches_from(["sima-init", "--config", "/tmp/test.yml", "single"]);
+        let cmdline = KernelCmdline::parse("sima.config=/etc/alt.yml sima.socket=/run/alt.sock");
+
+        let options = Options::resolve(&matches, &cmdline);
+
+        assert_eq!(options.config, PathBuf::from("/tmp/test.yml"));
+        assert_eq!(options.socket.as_deref(), Some("/run/alt.sock"));
+        assert_eq!(options.log_dir, PathBuf::from("/var/log/sima"));
+        assert_eq!(options.log_level, Level::Info);
+        assert!(options.command.is_empty());
+    }
+
+    #[test]
+    fn options_take_main_command_after_double_dash() {
+        let matches =
+            command().get_matches_from(["sima-init", "-v", "--", "nginx", "-g", "daemon off;"]);
+
+        let options = Options::resolve(&matches, &KernelCmdline::default());
+
+        assert_eq!(options.log_level, Level::Debug);
+        assert_eq!(options.command, vec!["nginx", "-g", "daemon off;"]);
+    }
+
+    #[test]
+    fn options_read_kernel_cmdline_overrides() {
+        let matches = command().get_matches_from(["sima-init"]);
+        let cmdline = KernelCmdline::parse("sima.log_dir=/run/log sima.verbose sima.target=rescue");
+
+        let options = Options::resolve(&matches, &cmdline);
+
+        assert_eq!(options.config, PathBuf::from("/etc/sima.yml"));
+        assert_eq!(options.log_dir, PathBuf::from("/run/log"));
+        assert_eq!(options.target.as_deref(), Some("rescue"));
+        assert_eq!(options.log_level, Level::Debug);
+
+        let options = Options::resolve(
+            &command().get_matches_from(["sima-init"]),
+            &KernelCmdline::parse("sima.verbose=0"),
+        );
+        assert_eq!(options.log_level, Level::Info);
+    }
+
+    #[test]
+    fn options_read_boot_debugging_parameters() {
+        let matches = command().get_matches_from(["sima-init", "single"]);
+        let cmdline = KernelCmdline::parse(
+            "sima.target=graphical sima.mask=kiosk,updater sima.wants=sshd sima.break sima.log_level=trace sima.crash_action=emergency",
+        );
+
+        let options = Options::resolve(&matches, &cmdline);
+
+        assert_eq!(options.target.as_deref(), Some("rescue"));
+        assert_eq!(options.masked, vec!["kiosk", "updater"]);
+        assert_eq!(options.wants, vec!["sshd"]);
+        assert!(options.break_shell);
+        assert!(!options.emergency);
+        assert_eq!(options.log_level, Level::Trace);
+        assert_eq!(options.crash_action, CrashAction::Emergency);
+
+        let options = Options::resolve(
+            &command().get_matches_from(["sima-init", "--log-level", "warn"]),
+            &KernelCmdline::parse("emergency sima.log_level=bogus"),
+        );
+        assert!(options.emergency);
+        assert_eq!(options.log_level, Level::Warn);
+        assert_eq!(options.emergency_shell, PathBuf::from("/bin/sh"));
+        assert_eq!(options.crash_action, CrashAction::Freeze);
+    }
+
+    #[test]
+    fn supervisor_uses_per_user_paths() {
+        let matches = command().get_matches_from(["sima-init", "--supervisor"]);
+
+        let options = Options::resolve(&matches, &KernelCmdline::default());
+
+        assert!(options.supervisor);
+        assert!(options.log_dir.ends_with("sima/log"));
+        assert_ne!(options.state_dir, PathBuf::from("/var/lib/sima"));
+        assert!(
+            options
+                .socket
+                .as_deref()
+                .is_some_and(|socket| socket.ends_with(".sock") && socket != "/run/sima.sock")
+        );
+    }
+}
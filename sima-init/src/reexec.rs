@@ -0,0 +1,286 @@
+use crate::config::ServiceConfig;
+use crate::shutdown;
+use anyhow::{Context, Result, bail};
+use nix::sys::memfd::{MFdFlags, memfd_create};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::{Pid, getpid};
+use serde::{Deserialize, Serialize};
+use sima_proto::ScheduledShutdown;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Option that hands the saved state to the new sima-init.
+const DESERIALIZE_ARG: &str = "--deserialize";
+/// What /proc/self/exe shows once the binary was replaced on disk, as an upgrade does.
+const DELETED_SUFFIX: &str = " (deleted)";
+
+/// What the service manager carries across `simactl daemon-reexec`. Services keep
+/// running as children of the same PID, so only their bookkeeping has to be passed on.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    /// Definitions of the loaded services, including template instances and the
+    /// entrypoint, which are not in the manifest.
+    pub configs: Vec<ServiceConfig>,
+    pub services: Vec<SavedService>,
+    pub runtime_masked: BTreeSet<String>,
+    pub boot_target: Option<String>,
+    pub exit_code: Option<i32>,
+    pub schedule: Option<ScheduledShutdown>,
+    /// Mount points mapped to why they failed to mount.
+    pub mount_failures: BTreeMap<String, String>,
+    /// The IPC socket, still listening, so that clients are not refused meanwhile.
+    #[serde(default)]
+    pub listener: Option<SavedListener>,
+}
+
+/// A listening socket left open across exec.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedListener {
+    pub fd: RawFd,
+    pub path: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedService {
+    pub name: String,
+    pub pid: Option<i32>,
+    pub running: bool,
+    pub needs_restart: bool,
+    pub removed: bool,
+    pub skipped: Option<String>,
+    pub failed: Option<String>,
+}
+
+/// Writes `state` to a memfd that survives exec and replaces the process with the
+/// sima-init binary now installed, with the same arguments. Only returns on failure.
+pub fn exec(state: &SavedState) -> Result<Infallible> {
+    let fd = memfd_create("sima-state", MFdFlags::empty()).context("failed to create a memfd")?;
+    let mut memfd = File::from(fd);
+    serde_json::to_writer(&mut memfd, state).context("failed to save the state")?;
+    memfd.flush()?;
+
+    let exe = executable()?;
+    spdlog::default_logger().flush();
+    let err = Command::new(&exe)
+        .args(arguments(std::env::args_os().skip(1), memfd.as_raw_fd()))
+        .exec();
+    Err(err).with_context(|| format!("failed to execute {}", exe.display()))
+}
+
+/// Reads the state left by the previous sima-init in `fd` and closes it.
+pub fn load(fd: RawFd) -> Result<SavedState> {
+    let path = format!("/proc/self/fd/{fd}");
+    let mut text = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .with_context(|| format!("failed to read {path}"))?;
+    // Services started from here on must not inherit it.
+    nix::unistd::close(fd).with_context(|| format!("failed to close fd {fd}"))?;
+    serde_json::from_str(&text).context("failed to parse the saved state")
+}
+
+/// The binary to execute: the path we were started from, even if it has since been
+/// replaced by a new version.
+pub fn executable() -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("failed to find the sima-init binary")?;
+    let exe = match exe
+        .to_str()
+        .and_then(|exe| exe.strip_suffix(DELETED_SUFFIX))
+    {
+        Some(path) => PathBuf::from(path),
+        None => exe,
+    };
+    if !exe.is_file() {
+        bail!("{} does not exist", exe.display());
+    }
+    Ok(exe)
+}
+
+/// Fails unless the sima-init at `exe` accepts the state option. Options it does not
+/// know are ignored by PID 1, so an older binary would start every service again.
+pub fn check_binary(exe: &Path) -> Result<()> {
+    // `--version` exits once the arguments before it have been accepted.
+    let output = Command::new(exe)
+        .args([DESERIALIZE_ARG, "0", "--version"])
+        .output()
+        .with_context(|| format!("failed to run {}", exe.display()))?;
+    if !output.status.success() {
+        bail!(
+            "{} does not support {DESERIALIZE_ARG}; restart the system to upgrade to it",
+            exe.display()
+        );
+    }
+    Ok(())
+}
+
+/// Stops the processes left by the sima-init that executed us when its state could not
+/// be read, so that they do not keep running untracked next to the services started
+/// again. Services run in process groups of their own, which are signalled as a whole.
+pub fn stop_children() {
+    for signal in [Signal::SIGTERM, Signal::SIGKILL] {
+        let children = children();
+        if children.is_empty() {
+            return;
+        }
+        eprintln!(
+            "Sending {} to {} process(es) left running",
+            signal,
+            children.len()
+        );
+        for child in children {
+            if kill(Pid::from_raw(-child.as_raw()), signal).is_err() {
+                let _ = kill(child, signal);
+            }
+        }
+        if shutdown::wait_for_processes(shutdown::KILL_TIMEOUT) {
+            return;
+        }
+    }
+}
+
+/// Our child processes, found by their parent in /proc.
+fn children() -> Vec<Pid> {
+    let me = getpid().as_raw();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| {
+            fs::read_to_string(format!("/proc/{pid}/stat"))
+                .is_ok_and(|stat| parent_pid(&stat) == Some(me))
+        })
+        .map(Pid::from_raw)
+        .collect()
+}
+
+/// The parent PID in the contents of /proc/<pid>/stat. The command name before it is in
+/// parentheses and may contain anything, including parentheses and spaces.
+fn parent_pid(stat: &str) -> Option<i32> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// `args` with the state option of an earlier re-exec replaced by one for `fd`.
+fn arguments(args: impl IntoIterator<Item = OsString>, fd: RawFd) -> Vec<OsString> {
+    let mut result = vec![
+        OsString::from(DESERIALIZE_ARG),
+        OsString::from(fd.to_string()),
+    ];
+    result.extend(without_state(args));
+    result
+}
+
+/// The arguments sima-init was started with, to run it afresh without the state of an
+/// earlier re-exec, whose descriptor is closed by now.
+pub fn fresh_arguments() -> Vec<OsString> {
+    without_state(std::env::args_os().skip(1))
+}
+
+/// `args` without the state option of an earlier re-exec. Words after `--` belong to
+/// the entrypoint and are kept as they are.
+fn without_state(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut result = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            result.push(arg);
+            result.extend(args);
+            break;
+        }
+        if arg == DESERIALIZE_ARG {
+            args.next();
+        } else if !arg
+            .to_str()
+            .is_some_and(|arg| arg.starts_with(&format!("{DESERIALIZE_ARG}=")))
+        {
+            result.push(arg);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os(words: &[&str]) -> Vec<OsString> {
+        words.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn arguments_replace_the_previous_state_fd() {
+        let args = os(&[
+            "--deserialize",
+            "3",
+            "-v",
+            "--deserialize=4",
+            "--",
+            "app",
+            "--deserialize",
+            "5",
+        ]);
+        assert_eq!(
+            arguments(args, 7),
+            os(&[
+                "--deserialize",
+                "7",
+                "-v",
+                "--",
+                "app",
+                "--deserialize",
+                "5"
+            ])
+        );
+    }
+
+    #[test]
+    fn without_state_drops_only_the_state_option() {
+        let args = os(&["-v", "--deserialize", "3", "--", "app", "--deserialize=4"]);
+        assert_eq!(
+            without_state(args),
+            os(&["-v", "--", "app", "--deserialize=4"])
+        );
+        assert_eq!(without_state(os(&["--deserialize=3"])), os(&[]));
+    }
+
+    #[test]
+    fn parent_pid_skips_the_command_name() {
+        assert_eq!(parent_pid("42 (sleep) S 1 42 42 0 -1"), Some(1));
+        assert_eq!(parent_pid("43 (a) b (c) R 17 43 43 0 -1"), Some(17));
+        assert_eq!(parent_pid("44 (truncated"), None);
+    }
+
+    #[test]
+    fn saved_state_round_trips_through_json() {
+        let state = SavedState {
+            services: vec![SavedService {
+                name: "sshd".to_string(),
+                pid: Some(42),
+                running: true,
+                needs_restart: false,
+                removed: false,
+                skipped: None,
+                failed: None,
+            }],
+            runtime_masked: BTreeSet::from(["getty@tty1".to_string()]),
+            boot_target: Some("multi-user".to_string()),
+            listener: Some(SavedListener {
+                fd: 5,
+                path: "/run/sima.sock".to_string(),
+            }),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&state).expect("state should serialize");
+        let loaded: SavedState = serde_json::from_str(&json).expect("state should parse");
+        assert_eq!(loaded, state);
+    }
+}
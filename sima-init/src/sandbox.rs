@@ -0,0 +1,431 @@
+use crate::config::{ProtectSystem, ServiceConfig};
+use anyhow::{Context, Result, bail};
+use nix::mount::{MsFlags, mount};
+use nix::sched::{CloneFlags, unshare};
+use std::convert::Infallible;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Argument that switches sima-init into the sandbox helper.
+///
+/// Mount namespaces have to be set up in the child between fork and exec. Since sima-init
+/// is free of unsafe code it cannot use `pre_exec`, so services with filesystem sandboxing
+/// are started through `sima-init --sandbox-exec <options> -- <program>` instead, which
+/// prepares the namespace and then execs the real program.
+pub const SANDBOX_EXEC_ARG: &str = "--sandbox-exec";
+
+const API_FILESYSTEMS: [&str; 3] = ["/dev", "/proc", "/sys"];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Sandbox {
+    private_tmp: bool,
+    protect_system: Option<ProtectSystem>,
+    read_only: Vec<PathBuf>,
+    read_write: Vec<PathBuf>,
+    inaccessible: Vec<PathBuf>,
+    binds: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Sandbox {
+    /// Returns `None` when the service does not request any isolation.
+    pub fn from_config(config: &ServiceConfig) -> Result<Option<Self>> {
+        let binds = config
+            .bind_paths
+            .iter()
+            .map(|spec| parse_bind(spec))
+            .collect::<Result<Vec<_>>>()?;
+
+        let sandbox = Self {
+            private_tmp: config.private_tmp,
+            protect_system: config.protect_system,
+            read_only: config.read_only_paths.clone(),
+            read_write: config.read_write_paths.clone(),
+            inaccessible: config.inaccessible_paths.clone(),
+            binds,
+        };
+
+        Ok((sandbox != Self::default()).then_some(sandbox))
+    }
+
+    /// Builds the command that runs `program` inside this sandbox.
+    pub fn command(&self, program: &str, args: &[&str]) -> Result<Command> {
+        let exe = std::env::current_exe().context("failed to locate sima-init executable")?;
+        let mut command = Command::new(exe);
+        command
+            .arg(SANDBOX_EXEC_ARG)
+            .args(self.to_args())
+            .arg("--")
+            .arg(program)
+            .args(args);
+        Ok(command)
+    }
+
+    fn to_args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        if self.private_tmp {
+            args.push("--private-tmp".into());
+        }
+        if let Some(protect) = self.protect_system {
+            args.push("--protect-system".into());
+            args.push(
+                match protect {
+                    ProtectSystem::Full => "full",
+                    ProtectSystem::Strict => "strict",
+                }
+                .into(),
+            );
+        }
+        for (flag, paths) in [
+            ("--read-only", &self.read_only),
+            ("--read-write", &self.read_write),
+            ("--inaccessible", &self.inaccessible),
+        ] {
+            for path in paths {
+                args.push(flag.into());
+                args.push(path.into());
+            }
+        }
+        for (source, dest) in &self.binds {
+            let mut spec = OsString::from(source);
+            spec.push(":");
+            spec.push(dest);
+            args.push("--bind".into());
+            args.push(spec);
+        }
+        args
+    }
+
+    fn from_args(args: &[String]) -> Result<(Self, &[String])> {
+        let mut sandbox = Self::default();
+        let mut iter = args.iter().enumerate();
+
+        while let Some((index, arg)) = iter.next() {
+            if arg == "--" {
+                return Ok((sandbox, &args[index + 1..]));
+            }
+            if arg == "--private-tmp" {
+                sandbox.private_tmp = true;
+                continue;
+            }
+
+            let Some((_, value)) = iter.next() else {
+                bail!("missing value for sandbox option `{arg}`");
+            };
+            match arg.as_str() {
+                "--protect-system" => {
+                    sandbox.protect_system = Some(match value.as_str() {
+                        "full" => ProtectSystem::Full,
+                        "strict" => ProtectSystem::Strict,
+                        other => bail!("invalid protect_system value `{other}`"),
+                    });
+                }
+                "--read-only" => sandbox.read_only.push(value.into()),
+                "--read-write" => sandbox.read_write.push(value.into()),
+                "--inaccessible" => sandbox.inaccessible.push(value.into()),
+                "--bind" => sandbox.binds.push(parse_bind(value)?),
+                other => bail!("unknown sandbox option `{other}`"),
+            }
+        }
+
+        bail!("missing `--` before the sandboxed command")
+    }
+
+    /// The mount operations that set up this sandbox, in order.
+    ///
+    /// Bind mounts come first so that `read_only_paths` can cover them, and are made
+    /// writable again after `protect_system`, which would otherwise seal them along with
+    /// the rest of the system.
+    fn steps(&self) -> Vec<Step> {
+        let mut steps: Vec<Step> = self
+            .binds
+            .iter()
+            .map(|(source, dest)| Step::Bind(source.clone(), dest.clone()))
+            .collect();
+
+        match self.protect_system {
+            Some(ProtectSystem::Full) => {
+                for path in ["/usr", "/boot", "/etc"] {
+                    if Path::new(path).exists() {
+                        steps.push(Step::Remount(path.into(), true));
+                    }
+                }
+            }
+            Some(ProtectSystem::Strict) => steps.push(Step::Remount("/".into(), true)),
+            None => {}
+        }
+        if self.protect_system.is_some() {
+            for (_, dest) in &self.binds {
+                steps.push(Step::Remount(dest.clone(), false));
+            }
+        }
+        for path in &self.read_only {
+            steps.push(Step::Remount(path.clone(), true));
+        }
+        for path in &self.read_write {
+            steps.push(Step::Remount(path.clone(), false));
+        }
+
+        if self.private_tmp {
+            for path in ["/tmp", "/var/tmp"] {
+                if Path::new(path).is_dir() {
+                    steps.push(Step::PrivateTmp(path.into()));
+                }
+            }
+        }
+
+        for path in &self.inaccessible {
+            steps.push(Step::Inaccessible(path.clone()));
+        }
+        steps
+    }
+
+    fn apply(&self) -> Result<()> {
+        unshare(CloneFlags::CLONE_NEWNS).context("failed to unshare mount namespace")?;
+        mount::<str, str, str, str>(None, "/", None, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None)
+            .context("failed to make mounts private")?;
+
+        for step in self.steps() {
+            match step {
+                Step::Bind(source, dest) => bind_mount(&source, &dest)?,
+                Step::Remount(path, read_only) => make_read_only(&path, read_only)?,
+                Step::PrivateTmp(path) => {
+                    mount_tmpfs(&path, "mode=1777", MsFlags::MS_NOSUID | MsFlags::MS_NODEV)?
+                }
+                Step::Inaccessible(path) => make_inaccessible(&path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One mount operation of [`Sandbox::steps`].
+#[derive(Debug, PartialEq)]
+enum Step {
+    /// Bind `source` to the destination.
+    Bind(PathBuf, PathBuf),
+    /// Remount a path and everything below it read-only (`true`) or read-write.
+    Remount(PathBuf, bool),
+    PrivateTmp(PathBuf),
+    Inaccessible(PathBuf),
+}
+
+/// Entry point of the sandbox helper; `args` are the arguments after [`SANDBOX_EXEC_ARG`].
+pub fn exec(args: &[String]) -> ! {
+    let Err(e) = exec_sandboxed(args);
+    eprintln!("sima sandbox: {e:#}");
+    std::process::exit(1);
+}
+
+fn exec_sandboxed(args: &[String]) -> Result<Infallible> {
+    let (sandbox, command) = Sandbox::from_args(args)?;
+    let Some((program, program_args)) = command.split_first() else {
+        bail!("no command given to sandbox");
+    };
+    sandbox.apply()?;
+    let err = Command::new(program).args(program_args).exec();
+    Err(err).with_context(|| format!("failed to execute {program}"))
+}
+
+fn parse_bind(spec: &str) -> Result<(PathBuf, PathBuf)> {
+    let (source, dest) = spec.split_once(':').unwrap_or((spec, spec));
+    if !source.starts_with('/') || !dest.starts_with('/') {
+        bail!("invalid bind path `{spec}`: paths must be absolute");
+    }
+    Ok((source.into(), dest.into()))
+}
+
+fn bind_mount(source: &Path, dest: &Path) -> Result<()> {
+    mount::<Path, Path, str, str>(
+        Some(source),
+        dest,
+        None,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None,
+    )
+    .with_context(|| format!("failed to bind {} to {}", source.display(), dest.display()))
+}
+
+fn mount_tmpfs(target: &Path, data: &str, flags: MsFlags) -> Result<()> {
+    mount(Some("tmpfs"), target, Some("tmpfs"), flags, Some(data))
+        .with_context(|| format!("failed to mount tmpfs on {}", target.display()))
+}
+
+/// Remounts `path` and every mount below it read-only (or read-write again).
+///
+/// When sealing `/`, the API file systems are left untouched.
+fn make_read_only(path: &Path, read_only: bool) -> Result<()> {
+    bind_mount(path, path)?;
+
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .context("failed to read /proc/self/mountinfo")?;
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT;
+    if read_only {
+        flags |= MsFlags::MS_RDONLY;
+    }
+
+    for (target, locked) in mount_points_below(&mountinfo, path) {
+        if path == Path::new("/") && API_FILESYSTEMS.iter().any(|api| target.starts_with(api)) {
+            continue;
+        }
+        // Remounts inside a user namespace must keep the flags that are locked by the parent.
+        mount::<str, Path, str, str>(None, &target, None, flags | locked, None)
+            .with_context(|| format!("failed to remount {}", target.display()))?;
+    }
+    Ok(())
+}
+
+fn make_inaccessible(path: &Path) -> Result<()> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to stat {}", path.display()));
+        }
+    };
+
+    if metadata.is_dir() {
+        mount(
+            Some("tmpfs"),
+            path,
+            Some("tmpfs"),
+            MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            Some("mode=000"),
+        )
+        .with_context(|| format!("failed to hide {}", path.display()))
+    } else {
+        bind_mount(Path::new("/dev/null"), path)?;
+        mount::<str, Path, str, str>(
+            None,
+            path,
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            None,
+        )
+        .with_context(|| format!("failed to hide {}", path.display()))
+    }
+}
+
+/// Mount points from `/proc/self/mountinfo` at or below `path` with their per-mount flags,
+/// outermost first.
+pub fn mount_points_below(mountinfo: &str, path: &Path) -> Vec<(PathBuf, MsFlags)> {
+    let mut targets: Vec<(PathBuf, MsFlags)> = mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ').skip(4);
+            let target = PathBuf::from(unescape_mountinfo(fields.next()?));
+            let flags = fields
+                .next()?
+                .split(',')
+                .fold(MsFlags::empty(), |flags, opt| {
+                    flags
+                        | match opt {
+                            "nosuid" => MsFlags::MS_NOSUID,
+                            "nodev" => MsFlags::MS_NODEV,
+                            "noexec" => MsFlags::MS_NOEXEC,
+                            "noatime" => MsFlags::MS_NOATIME,
+                            "nodiratime" => MsFlags::MS_NODIRATIME,
+                            "relatime" => MsFlags::MS_RELATIME,
+                            _ => MsFlags::empty(),
+                        }
+                });
+            Some((target, flags))
+        })
+        .filter(|(target, _)| target.starts_with(path))
+        .collect();
+    targets.sort_by_key(|(target, _)| target.components().count());
+    targets.dedup_by(|a, b| a.0 == b.0);
+    targets
+}
+
+/// Undoes the octal escapes used for whitespace and backslashes in mount tables.
+pub fn unescape_mountinfo(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sandbox, Step, mount_points_below, parse_bind};
+    use crate::config::ProtectSystem;
+    use nix::mount::MsFlags;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn sandbox_args_round_trip() {
+        let sandbox = Sandbox {
+            private_tmp: true,
+            protect_system: Some(ProtectSystem::Full),
+            read_only: vec!["/srv".into()],
+            read_write: vec!["/srv/data".into()],
+            inaccessible: vec!["/home".into()],
+            binds: vec![("/var/lib/app".into(), "/data".into())],
+        };
+
+        let mut args: Vec<String> = sandbox
+            .to_args()
+            .into_iter()
+            .map(|arg| arg.into_string().expect("utf-8 argument"))
+            .collect();
+        args.extend(["--".to_string(), "/bin/sh".to_string()]);
+
+        let (parsed, command) = Sandbox::from_args(&args).expect("args should parse");
+        assert_eq!(parsed, sandbox);
+        assert_eq!(command, ["/bin/sh".to_string()]);
+    }
+
+    #[test]
+    fn binds_stay_writable_under_strict_protection() {
+        let sandbox = Sandbox {
+            protect_system: Some(ProtectSystem::Strict),
+            read_only: vec!["/srv".into()],
+            binds: vec![("/var/lib/app".into(), "/data".into())],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            sandbox.steps(),
+            vec![
+                Step::Bind("/var/lib/app".into(), "/data".into()),
+                Step::Remount("/".into(), true),
+                Step::Remount("/data".into(), false),
+                Step::Remount("/srv".into(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_bind_defaults_destination_to_source() {
+        assert_eq!(
+            parse_bind("/srv").expect("bind should parse"),
+            (PathBuf::from("/srv"), PathBuf::from("/srv"))
+        );
+        assert!(parse_bind("relative:/srv").is_err());
+    }
+
+    #[test]
+    fn mount_points_below_orders_parents_first() {
+        let mountinfo = "\
+24 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+30 24 0:26 / /srv/my\\040data rw,nosuid,nodev shared:9 - tmpfs tmpfs rw
+25 24 0:5 / /srv rw shared:2 - tmpfs tmpfs rw
+26 24 0:6 / /proc rw shared:3 - proc proc rw
+";
+
+        assert_eq!(
+            mount_points_below(mountinfo, Path::new("/srv")),
+            vec![
+                (PathBuf::from("/srv"), MsFlags::empty()),
+                (
+                    PathBuf::from("/srv/my data"),
+                    MsFlags::MS_NOSUID | MsFlags::MS_NODEV
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use nix::libc;
+use nix::sys::reboot::RebootMode;
+use sima_proto::{ScheduledShutdown, ShutdownKind};
+use spdlog::{info, warn};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const UTMP_PATH: &str = "/run/utmp";
+/// Keeps users from logging in, through pam_nologin.
+const NOLOGIN_PATH: &str = "/run/nologin";
+/// How long before the deadline logins are refused.
+const NOLOGIN_LEAD: Duration = Duration::from_secs(5 * 60);
+/// Logged-in users are warned when this much time is left, besides when the shutdown is
+/// scheduled or cancelled.
+const WARNINGS: [Duration; 6] = [
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(30 * 60),
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(10 * 60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(60),
+];
+
+/// Layout of the records in utmp, which is `struct utmpx` of the C library.
+const UTMP_SIZE: usize = size_of::<libc::utmpx>();
+const UT_TYPE: usize = std::mem::offset_of!(libc::utmpx, ut_type);
+const UT_LINE: usize = std::mem::offset_of!(libc::utmpx, ut_line);
+const UT_LINE_LEN: usize = size_of::<[libc::c_char; libc::__UT_LINESIZE]>();
+
+/// A shutdown scheduled with `simactl poweroff|reboot|halt --at|--in`.
+pub struct Schedule {
+    pub shutdown: ScheduledShutdown,
+    /// Index of the next entry of `WARNINGS` to send.
+    next_warning: usize,
+    /// Whether to create /run/nologin, which only init does.
+    refuse_logins: bool,
+    /// Whether /run/nologin was created.
+    nologin: bool,
+}
+
+impl Schedule {
+    /// Announces `shutdown` to logged-in users.
+    pub fn new(shutdown: ScheduledShutdown, refuse_logins: bool) -> Self {
+        let mut schedule = Self {
+            shutdown,
+            next_warning: 0,
+            refuse_logins,
+            nologin: false,
+        };
+        let remaining = schedule.remaining(SystemTime::now());
+        schedule.skip_warnings(remaining);
+        info!(
+            "Scheduled {} in {}",
+            schedule.shutdown.kind.verb(),
+            format_duration(remaining)
+        );
+        wall(&schedule.message(remaining));
+        schedule.tick(SystemTime::now());
+        schedule
+    }
+
+    /// Takes over `shutdown` from the sima-init that executed us, without announcing it
+    /// again.
+    pub fn resume(shutdown: ScheduledShutdown, refuse_logins: bool) -> Self {
+        let mut schedule = Self {
+            shutdown,
+            next_warning: 0,
+            refuse_logins,
+            nologin: refuse_logins && Path::new(NOLOGIN_PATH).exists(),
+        };
+        schedule.skip_warnings(schedule.remaining(SystemTime::now()));
+        schedule
+    }
+
+    fn deadline(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.shutdown.at)
+    }
+
+    fn remaining(&self, now: SystemTime) -> Duration {
+        self.deadline().duration_since(now).unwrap_or_default()
+    }
+
+    /// How long until something is due: a warning, refusing logins or the shutdown.
+    pub fn next_wakeup(&self, now: SystemTime) -> Duration {
+        let remaining = self.remaining(now);
+        let mut wakeup = remaining;
+        if let Some(&lead) = WARNINGS.get(self.next_warning) {
+            wakeup = wakeup.min(remaining.saturating_sub(lead));
+        }
+        if self.refuse_logins && !self.nologin {
+            wakeup = wakeup.min(remaining.saturating_sub(NOLOGIN_LEAD));
+        }
+        wakeup
+    }
+
+    /// Sends the warnings and creates /run/nologin as they fall due. Returns true once
+    /// the deadline has passed.
+    pub fn tick(&mut self, now: SystemTime) -> bool {
+        let remaining = self.remaining(now);
+        if remaining.is_zero() {
+            return true;
+        }
+        if WARNINGS
+            .get(self.next_warning)
+            .is_some_and(|&lead| remaining <= lead)
+        {
+            self.skip_warnings(remaining);
+            wall(&self.message(remaining));
+        }
+        if self.refuse_logins && !self.nologin && remaining <= NOLOGIN_LEAD {
+            self.nologin = true;
+            let message = format!(
+                "The system is going down to {}; logins are disabled.\n",
+                self.shutdown.kind.verb()
+            );
+            match fs::write(NOLOGIN_PATH, message) {
+                Ok(()) => info!("Created {} to refuse logins", NOLOGIN_PATH),
+                Err(e) => warn!("Failed to create {}: {}", NOLOGIN_PATH, e),
+            }
+        }
+        false
+    }
+
+    /// Tells logged-in users and allows logins again.
+    pub fn cancel(self) {
+        info!("Cancelled the scheduled {}", self.shutdown.kind.verb());
+        wall(&format!(
+            "The scheduled {} has been cancelled.",
+            self.shutdown.kind.verb()
+        ));
+        if self.nologin
+            && let Err(e) = fs::remove_file(NOLOGIN_PATH)
+        {
+            warn!("Failed to remove {}: {}", NOLOGIN_PATH, e);
+        }
+    }
+
+    /// Moves past the warnings for more time than `remaining`.
+    fn skip_warnings(&mut self, remaining: Duration) {
+        while WARNINGS
+            .get(self.next_warning)
+            .is_some_and(|&lead| remaining <= lead)
+        {
+            self.next_warning += 1;
+        }
+    }
+
+    fn message(&self, remaining: Duration) -> String {
+        format!(
+            "The system will {} in {}!",
+            self.shutdown.kind.verb(),
+            format_duration(remaining)
+        )
+    }
+}
+
+/// `remaining` rounded to the nearest minute, or in seconds under a minute.
+fn format_duration(remaining: Duration) -> String {
+    let secs = remaining.as_secs();
+    if secs < 60 {
+        return format!("{secs} seconds");
+    }
+    match (secs + 30) / 60 {
+        1 => "1 minute".to_string(),
+        minutes => format!("{minutes} minutes"),
+    }
+}
+
+/// Terminals of the users logged in according to utmp, e.g. `pts/0`.
+fn login_terminals(utmp: &[u8]) -> Vec<String> {
+    utmp.chunks_exact(UTMP_SIZE)
+        .filter(|record| {
+            libc::c_short::from_ne_bytes([record[UT_TYPE], record[UT_TYPE + 1]])
+                == libc::USER_PROCESS
+        })
+        .filter_map(|record| {
+            let line = &record[UT_LINE..UT_LINE + UT_LINE_LEN];
+            let len = line.iter().position(|&b| b == 0).unwrap_or(line.len());
+            let line = String::from_utf8_lossy(&line[..len]).into_owned();
+            // A line is a device under /dev; anything else is not a terminal to write to.
+            (!line.is_empty() && !line.contains("..")).then_some(line)
+        })
+        .collect()
+}
+
+/// Writes `message` to every logged-in terminal, as wall(1) does. Terminals that would
+/// block are skipped rather than holding up init.
+fn wall(message: &str) {
+    let utmp = fs::read(UTMP_PATH).unwrap_or_default();
+    let mut terminals = login_terminals(&utmp);
+    terminals.sort();
+    terminals.dedup();
+    let text = format!("\r\n\x07Broadcast message from sima-init:\r\n\r\n{message}\r\n\r\n");
+    for terminal in terminals {
+        let path = Path::new("/dev").join(&terminal);
+        let result = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&path)
+            .and_then(|mut tty| tty.write_all(text.as_bytes()));
+        if let Err(e) = result {
+            warn!("Failed to write to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// How the kernel carries out a shutdown of `kind`.
+pub fn reboot_mode(kind: ShutdownKind) -> RebootMode {
+    match kind {
+        ShutdownKind::Poweroff => RebootMode::RB_POWER_OFF,
+        ShutdownKind::Reboot => RebootMode::RB_AUTOBOOT,
+        ShutdownKind::Halt => RebootMode::RB_HALT_SYSTEM,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ut_type: libc::c_short, line: &str) -> Vec<u8> {
+        let mut record = vec![0; UTMP_SIZE];
+        record[UT_TYPE..UT_TYPE + 2].copy_from_slice(&ut_type.to_ne_bytes());
+        record[UT_LINE..UT_LINE + line.len()].copy_from_slice(line.as_bytes());
+        record
+    }
+
+    #[test]
+    fn login_terminals_reads_user_processes_from_utmp() {
+        let utmp = [
+            record(libc::USER_PROCESS, "pts/0"),
+            record(libc::BOOT_TIME, "~"),
+            record(libc::USER_PROCESS, "tty1"),
+            record(libc::USER_PROCESS, "../etc/passwd"),
+        ]
+        .concat();
+        assert_eq!(login_terminals(&utmp), vec!["pts/0", "tty1"]);
+    }
+
+    #[test]
+    fn schedule_wakes_up_for_warnings_nologin_and_the_deadline() {
+        let now = SystemTime::now();
+        let at = now + Duration::from_secs(12 * 60);
+        let mut schedule = Schedule {
+            shutdown: ScheduledShutdown {
+                kind: ShutdownKind::Reboot,
+                at: at
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            },
+            next_warning: 0,
+            refuse_logins: true,
+            nologin: false,
+        };
+        schedule.skip_warnings(schedule.remaining(now));
+        // The 10 minute warning is next.
+        let wakeup = schedule.next_wakeup(now);
+        assert!(wakeup > Duration::from_secs(60) && wakeup <= Duration::from_secs(2 * 60));
+        assert!(!schedule.tick(now));
+        assert!(schedule.tick(at + Duration::from_secs(1)));
+    }
+}
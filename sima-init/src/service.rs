@@ -1,21 +1,54 @@
-use crate::config::{ServiceConfig, SimaConfig};
+use crate::condition::{self, Host};
+use crate::config::{ENTRYPOINT_SERVICE, ServiceConfig, SimaConfig};
+use crate::console;
+use crate::enablement::EnablementStore;
 use crate::ipc::{IpcCommand, IpcServer, handle_client};
+use crate::logger::Log;
+use crate::mounts::{self, MountTable};
+use crate::options::Options;
+use crate::reexec::{self, SavedService, SavedState};
+use crate::sandbox::Sandbox;
+use crate::schedule::{self, Schedule};
+use crate::shutdown;
+use crate::signals::{self, SignalAction, SignalTable};
+use crate::target::{self, Targets};
+use crate::template;
 use anyhow::{Context, Result, bail};
-use nix::sys::reboot::{RebootMode, reboot};
+use nix::sys::reboot::RebootMode;
 use nix::sys::signal::{self, Signal};
 use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
-use nix::unistd::Pid;
-use sima_proto::ServiceInfo;
-use spdlog::{error, info, warn};
-use std::collections::HashMap;
+use nix::unistd::{Pid, getpgrp, tcgetpgrp, tcsetpgrp};
+use sima_proto::{ConfigDiff, Enablement, KexecImage, ServiceInfo};
+use spdlog::{Level, error, info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{CStr, CString};
+use std::io::IsTerminal;
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::Command;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::signal::unix::Signal as TokioSignal;
 use tokio::signal::unix::{SignalKind, signal as tokio_signal};
 use tokio::sync::mpsc;
 use tokio::time::timeout;
 
+/// Longest sleep while a shutdown is scheduled, so that a change of the clock is noticed.
+const SCHEDULE_RECHECK: Duration = Duration::from_secs(60);
+
+/// Longest wait for the reply to `daemon-reexec` to be written, should the client stop
+/// reading.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Signals relayed to the entrypoint while it runs instead of taking their action.
+const FORWARDED_SIGNALS: [Signal; 6] = [
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGWINCH,
+];
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ServiceStatus {
     Running,
@@ -25,6 +58,14 @@ pub enum ServiceStatus {
 pub struct ServiceState {
     pid: Option<Pid>,
     status: ServiceStatus,
+    /// Set by a config reload that changed the definition of a running service.
+    needs_restart: bool,
+    /// Set by a config reload that removed a running service; it is dropped on exit.
+    removed: bool,
+    /// The condition that was not met the last time the service was started.
+    skipped: Option<String>,
+    /// Why the last start failed: an unmet assertion or a spawn error.
+    failed: Option<String>,
 }
 
 impl Default for ServiceState {
@@ -32,6 +73,10 @@ impl Default for ServiceState {
         Self {
             pid: None,
             status: ServiceStatus::Stopped,
+            needs_restart: false,
+            removed: false,
+            skipped: None,
+            failed: None,
         }
     }
 }
@@ -40,10 +85,44 @@ pub struct ServiceManager {
     configs: HashMap<String, ServiceConfig>,
     states: HashMap<String, ServiceState>,
     pid_map: HashMap<Pid, String>,
+    templates: HashMap<String, ServiceConfig>,
+    enablement: EnablementStore,
+    host: Host,
+    targets: Targets,
+    /// Target started at boot: `--target`/`sima.target=`, else the manifest default.
+    boot_target: Option<String>,
+    manifest_path: PathBuf,
+    socket_path: Option<String>,
+    /// Running as a supervisor rather than PID 1: power off and reboot never reach the kernel.
+    supervisor: bool,
+    /// Main command of a container entrypoint; sima exits when it does.
+    entrypoint: Option<ServiceConfig>,
+    /// Exit status of the entrypoint, once it has exited.
+    exit_code: Option<i32>,
+    /// Services started at boot whether or not they are enabled or in the boot target.
+    wants: Vec<String>,
+    /// Entries of /etc/fstab, shown and controlled like services.
+    mounts: MountTable,
+    /// Actions for the signals sima receives: the defaults overridden by the manifest.
+    signals: SignalTable,
+    /// Relays received signals to the event loop, once it runs.
+    signal_tx: Option<mpsc::Sender<i32>>,
+    /// Signals that already have a relaying task.
+    listening: BTreeSet<i32>,
+    log_dir: PathBuf,
+    log_level: Level,
+    /// Shutdown requested for later with `--at` or `--in`.
+    schedule: Option<Schedule>,
+    /// Took over running services from the sima-init that executed us, so nothing is
+    /// started at boot.
+    restored: bool,
+    /// IPC socket taken over from the sima-init that executed us, until the event loop
+    /// listens on it.
+    ipc_server: Option<IpcServer>,
 }
 
 impl ServiceManager {
-    pub fn new(config: SimaConfig) -> Self {
+    pub fn new(config: SimaConfig, options: &Options, mounts: MountTable) -> Self {
         let mut configs = HashMap::new();
         let mut states = HashMap::new();
 
@@ -52,18 +131,164 @@ impl ServiceManager {
             configs.insert(sc.name.clone(), sc);
         }
 
+        let templates = config
+            .templates
+            .into_iter()
+            .map(|t| (t.name.clone(), t))
+            .collect();
+
+        let mut enablement = EnablementStore::load(&options.state_dir).unwrap_or_else(|e| {
+            error!("Failed to load enablement state: {:#}", e);
+            EnablementStore::empty(&options.state_dir)
+        });
+        for name in &options.masked {
+            info!("Masking {} for this boot", name);
+            enablement.mask_runtime(name);
+        }
+
+        let supervisor = options.supervisor || !options.command.is_empty();
+        let mut signals = signals::default_table(!supervisor, config.default_target.as_deref());
+        signals.extend(config.signals);
+
         Self {
             configs,
             states,
             pid_map: HashMap::new(),
+            templates,
+            enablement,
+            host: Host::detect(),
+            targets: config.targets,
+            boot_target: options.target.clone().or(config.default_target),
+            manifest_path: options.config.clone(),
+            socket_path: options.socket.clone(),
+            supervisor,
+            entrypoint: (!options.command.is_empty())
+                .then(|| ServiceConfig::entrypoint(options.command.clone())),
+            exit_code: None,
+            wants: options.wants.clone(),
+            mounts,
+            signals,
+            signal_tx: None,
+            listening: BTreeSet::new(),
+            log_dir: options.log_dir.clone(),
+            log_level: options.log_level,
+            schedule: None,
+            restored: false,
+            ipc_server: None,
+        }
+    }
+
+    /// Takes over the services of the sima-init that executed us, which are still
+    /// running as our children, then applies any change to the manifest.
+    pub fn restore(&mut self, saved: SavedState) {
+        // First, so that services started from here on do not inherit the socket.
+        if let Some(listener) = saved.listener {
+            self.ipc_server = IpcServer::adopt(listener)
+                .map_err(|e| warn!("Binding the IPC socket again: {:#}", e))
+                .ok();
+        }
+        self.configs = saved
+            .configs
+            .into_iter()
+            .map(|config| (config.name.clone(), config))
+            .collect();
+        // The program and arguments of the entrypoint are not saved; they are the same.
+        if let Some(entrypoint) = &self.entrypoint
+            && self.configs.contains_key(ENTRYPOINT_SERVICE)
+        {
+            self.configs
+                .insert(ENTRYPOINT_SERVICE.to_string(), entrypoint.clone());
+        }
+
+        self.states.clear();
+        self.pid_map.clear();
+        for service in saved.services {
+            let pid = service.pid.map(Pid::from_raw);
+            if let Some(pid) = pid {
+                self.pid_map.insert(pid, service.name.clone());
+            }
+            let state = ServiceState {
+                pid,
+                status: if service.running {
+                    ServiceStatus::Running
+                } else {
+                    ServiceStatus::Stopped
+                },
+                needs_restart: service.needs_restart,
+                removed: service.removed,
+                skipped: service.skipped,
+                failed: service.failed,
+            };
+            self.states.insert(service.name, state);
+        }
+
+        self.enablement.set_runtime_masked(saved.runtime_masked);
+        self.boot_target = saved.boot_target;
+        self.exit_code = saved.exit_code;
+        self.schedule = saved
+            .schedule
+            .map(|shutdown| Schedule::resume(shutdown, !self.supervisor));
+        for (name, failed) in saved.mount_failures {
+            self.mounts.set_failed(&name, failed);
+        }
+        self.restored = true;
+        info!(
+            "Took over {} service(s), {} running",
+            self.states.len(),
+            self.pid_map.len()
+        );
+
+        if let Err(e) = self.reload_config() {
+            warn!("Keeping the previous configuration: {:#}", e);
         }
     }
 
-    fn spawn_process(cmdline: &str, environment: Option<&[String]>) -> Result<Pid> {
-        let mut command = Command::new("/bin/sh");
-        command.arg("-c").arg(format!("exec {}", cmdline));
+    /// What `restore` needs to carry on where we stop.
+    fn save_state(&self) -> SavedState {
+        SavedState {
+            configs: self.configs.values().cloned().collect(),
+            services: self
+                .states
+                .iter()
+                .map(|(name, state)| SavedService {
+                    name: name.clone(),
+                    pid: state.pid.map(Pid::as_raw),
+                    running: state.status == ServiceStatus::Running,
+                    needs_restart: state.needs_restart,
+                    removed: state.removed,
+                    skipped: state.skipped.clone(),
+                    failed: state.failed.clone(),
+                })
+                .collect(),
+            runtime_masked: self.enablement.runtime_masked().clone(),
+            boot_target: self.boot_target.clone(),
+            exit_code: self.exit_code,
+            schedule: self.schedule.as_ref().map(|s| s.shutdown.clone()),
+            mount_failures: self
+                .mounts
+                .units()
+                .filter_map(|unit| Some((unit.name(), unit.failed.clone()?)))
+                .collect(),
+            listener: None,
+        }
+    }
 
-        if let Some(environment) = environment {
+    fn spawn_process(config: &ServiceConfig) -> Result<Pid> {
+        let script = format!("exec {}", config.cmdline);
+        let (program, args): (&str, Vec<&str>) = match config.argv.split_first() {
+            Some((program, args)) => (program, args.iter().map(String::as_str).collect()),
+            None => ("/bin/sh", vec!["-c", &script]),
+        };
+        let mut command = match Sandbox::from_config(config)? {
+            Some(sandbox) => sandbox.command(program, &args)?,
+            None => {
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+        };
+
+        if let Some(environment) = config.environment.as_deref() {
             command.envs(parse_environment(environment)?);
         }
 
@@ -72,13 +297,143 @@ impl ServiceManager {
         Ok(Pid::from_raw(child.id() as i32))
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        for (name, config) in &self.configs {
-            if let Some(state) = self.states.get_mut(name) {
-                Self::launch_service(name, config, state, &mut self.pid_map);
+    /// Starts the services and runs until shutdown. Returns the exit status of the
+    /// entrypoint, or 0 without one.
+    pub async fn run(&mut self) -> Result<i32> {
+        if !self.restored {
+            self.start_boot_services();
+        }
+
+        if let Err(e) = self.event_loop().await {
+            // Nothing would supervise the services any more.
+            self.broadcast_signal(Signal::SIGKILL);
+            return Err(e);
+        }
+        Ok(self.exit_code.unwrap_or(0))
+    }
+
+    /// Starts the boot target, or every enabled service, and the entrypoint.
+    fn start_boot_services(&mut self) {
+        let instances: Vec<String> = self
+            .enablement
+            .enabled()
+            .filter(|name| !self.configs.contains_key(*name))
+            .map(String::from)
+            .collect();
+        for name in instances {
+            if self.materialize_instance(&name).is_none() {
+                warn!("Enabled service {} not found in config", name);
+            }
+        }
+
+        let wanted = self.boot_services();
+        if let Some(target) = &self.boot_target
+            && wanted.is_some()
+        {
+            info!("Starting target {}", target);
+        }
+
+        let mut names: Vec<String> = self
+            .configs
+            .keys()
+            .filter(|name| wanted.as_ref().is_none_or(|wanted| wanted.contains(*name)))
+            .cloned()
+            .collect();
+        names.sort();
+        for name in &names {
+            if self.starts_at_boot(name, wanted.as_ref()) {
+                self.start_service(name);
+            } else {
+                info!("Service {} is not enabled or is masked, skipping", name);
+            }
+        }
+        for name in self.wants.clone() {
+            if !(names.contains(&name) && self.starts_at_boot(&name, wanted.as_ref())) {
+                info!("Starting {} as requested on the kernel command line", name);
+                self.start_service(&name);
+            }
+        }
+
+        if let Some(entrypoint) = self.entrypoint.clone() {
+            self.states
+                .insert(ENTRYPOINT_SERVICE.to_string(), ServiceState::default());
+            self.configs
+                .insert(ENTRYPOINT_SERVICE.to_string(), entrypoint);
+            self.start_service(ENTRYPOINT_SERVICE);
+            match self.states.get(ENTRYPOINT_SERVICE).and_then(|s| s.pid) {
+                Some(pid) => give_terminal(pid),
+                // As a shell does for a command it cannot run.
+                None => self.exit_code = Some(127),
+            }
+        }
+    }
+
+    /// The services of the boot target, or `None` to start every enabled service.
+    fn boot_services(&mut self) -> Option<BTreeSet<String>> {
+        let target = self.boot_target.clone()?;
+        match self.target_services(&target) {
+            Ok(wanted) => Some(wanted),
+            Err(e) => {
+                error!(
+                    "Failed to resolve target {}: {:#}; starting all enabled services",
+                    target, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Whether `name` is started at boot, given the services `wanted` by the boot target.
+    /// Being part of the target is reason enough, whatever `autostart` says, but masks
+    /// still apply.
+    fn starts_at_boot(&self, name: &str, wanted: Option<&BTreeSet<String>>) -> bool {
+        match wanted {
+            Some(wanted) => wanted.contains(name) && !self.enablement.is_masked(name),
+            None => self.is_enabled(name),
+        }
+    }
+
+    fn is_enabled(&self, name: &str) -> bool {
+        self.configs.get(name).is_some_and(|config| {
+            self.enablement.state(name, config.autostart) == Enablement::Enabled
+        })
+    }
+
+    /// Resolves target `name`, instantiating the template instances it wants.
+    fn target_services(&mut self, name: &str) -> Result<BTreeSet<String>> {
+        let wanted = target::resolve(&self.targets, name)?;
+        for service in &wanted {
+            if !self.configs.contains_key(service) && self.materialize_instance(service).is_none() {
+                warn!("Target {} wants unknown service {}", name, service);
+            }
+        }
+        Ok(wanted)
+    }
+
+    /// Starts the services of target `name` that are not masked and stops every other
+    /// service.
+    fn isolate(&mut self, name: &str) -> Result<()> {
+        let wanted = self.target_services(name)?;
+        info!("Isolating target {}", name);
+
+        let unwanted: Vec<String> = self
+            .states
+            .iter()
+            .filter(|(service, state)| {
+                state.status == ServiceStatus::Running && !wanted.contains(*service)
+            })
+            .map(|(service, _)| service.clone())
+            .collect();
+        for service in unwanted {
+            self.stop_service(&service);
+        }
+
+        for service in &wanted {
+            if !self.enablement.is_masked(service) {
+                self.start_service(service);
             }
         }
-        self.event_loop().await
+        Ok(())
     }
 
     fn launch_service(
@@ -86,27 +441,59 @@ impl ServiceManager {
         config: &ServiceConfig,
         state: &mut ServiceState,
         pid_map: &mut HashMap<Pid, String>,
+        host: &Host,
     ) {
         if state.status == ServiceStatus::Running {
             info!("Service {} is already running", name);
             return;
         }
+        if state.removed {
+            warn!("Service {} was removed from the configuration", name);
+            return;
+        }
+
+        state.skipped = None;
+        state.failed = None;
+        if let Some(condition) = condition::first_unmet(&config.conditions, host) {
+            info!("Skipping service {}: condition {} not met", name, condition);
+            state.skipped = Some(condition.to_string());
+            return;
+        }
+        if let Some(assertion) = condition::first_unmet(&config.assertions, host) {
+            error!("Service {} failed: assertion {} not met", name, assertion);
+            console::failed(&format!(
+                "Failed to start {name}: assertion {assertion} not met"
+            ));
+            state.failed = Some(format!("assertion {assertion} not met"));
+            return;
+        }
 
         info!("Starting service: {}", name);
-        match Self::spawn_process(&config.cmdline, config.environment.as_deref()) {
+        match Self::spawn_process(config) {
             Ok(pid) => {
                 info!("Service {} started (PID: {})", name, pid);
+                console::ok(&format!("Started {}", describe(name, config)));
                 state.pid = Some(pid);
                 state.status = ServiceStatus::Running;
+                state.needs_restart = false;
                 pid_map.insert(pid, name.to_string());
             }
             Err(e) => {
                 error!("Failed to start service {}: {}", name, e);
+                console::failed(&format!("Failed to start {}", describe(name, config)));
+                state.failed = Some(format!("{e:#}"));
             }
         }
     }
 
     fn stop_service(&mut self, name: &str) {
+        if self.mounts.contains(name) {
+            if let Err(e) = self.mounts.stop(name) {
+                error!("{:#}", e);
+            }
+            return;
+        }
+
         let Some(state) = self.states.get(name) else {
             warn!("Service {} not found", name);
             return;
@@ -119,15 +506,30 @@ impl ServiceManager {
 
         info!("Stopping service: {} (PID: {})", name, pid);
         let pgid = Pid::from_raw(-pid.as_raw());
-        if let Err(e) = signal::kill(pgid, Signal::SIGTERM) {
-            if e != nix::Error::ESRCH {
-                warn!("Failed to send SIGTERM to {}: {}", name, e);
-            }
+        if let Err(e) = signal::kill(pgid, Signal::SIGTERM)
+            && e != nix::Error::ESRCH
+        {
+            warn!("Failed to send SIGTERM to {}: {}", name, e);
         }
     }
 
     fn start_service(&mut self, name: &str) {
-        let Some(config) = self.configs.get(name).cloned() else {
+        if self.mounts.contains(name) {
+            // Failures are logged and shown in the status by the mount table.
+            let _ = self.mounts.start(name);
+            return;
+        }
+        if self.enablement.is_masked(name) {
+            warn!("Service {} is masked, refusing to start it", name);
+            return;
+        }
+
+        let Some(config) = self
+            .configs
+            .get(name)
+            .cloned()
+            .or_else(|| self.materialize_instance(name))
+        else {
             warn!("Service {} not found in config", name);
             return;
         };
@@ -135,8 +537,16 @@ impl ServiceManager {
         let Some(state) = self.states.get_mut(name) else {
             return;
         };
+        if state.status == ServiceStatus::Stopped
+            && let Err(e) = self.mounts.require(&config.requires_mounts_for)
+        {
+            error!("Service {} failed: {:#}", name, e);
+            console::failed(&format!("Failed to start {name}: {e:#}"));
+            state.failed = Some(format!("{e:#}"));
+            return;
+        }
 
-        Self::launch_service(name, &config, state, &mut self.pid_map);
+        Self::launch_service(name, &config, state, &mut self.pid_map, &self.host);
     }
 
     fn restart_service(&mut self, name: &str) {
@@ -148,6 +558,22 @@ impl ServiceManager {
     }
 
     fn get_status(&self) -> Vec<ServiceInfo> {
+        let mounted = mounts::mounted_targets();
+        let mounts = self.mounts.units().map(|unit| ServiceInfo {
+            name: unit.name(),
+            pid: None,
+            running: mounted.contains(&unit.entry.mount.target),
+            needs_restart: false,
+            skipped: None,
+            failed: unit.failed.clone(),
+            enablement: if unit.entry.auto {
+                Enablement::Enabled
+            } else {
+                Enablement::Disabled
+            },
+            mount: true,
+        });
+
         self.configs
             .keys()
             .map(|name| {
@@ -158,11 +584,150 @@ impl ServiceManager {
                     running: state
                         .map(|s| s.status == ServiceStatus::Running)
                         .unwrap_or(false),
+                    needs_restart: state.map(|s| s.needs_restart).unwrap_or(false),
+                    skipped: state.and_then(|s| s.skipped.clone()),
+                    failed: state.and_then(|s| s.failed.clone()),
+                    enablement: self
+                        .enablement
+                        .state(name, self.configs.get(name).is_some_and(|c| c.autostart)),
+                    mount: false,
                 }
             })
+            .chain(mounts)
             .collect()
     }
 
+    /// Applies `simactl enable|disable|mask|unmask`; the state is saved immediately.
+    /// Unknown names can still be masked, so a service can be blocked before it is installed.
+    fn update_enablement(
+        &mut self,
+        name: &str,
+        require_known: bool,
+        update: fn(&mut EnablementStore, &str) -> Result<()>,
+    ) -> Result<(), String> {
+        let autostart = self
+            .configs
+            .get(name)
+            .or_else(|| {
+                let (template_name, _) = template::split_instance(name)?;
+                self.templates.get(template_name)
+            })
+            .map(|config| config.autostart);
+        if autostart.is_none() && require_known {
+            return Err(format!("Service {name} not found in config"));
+        }
+
+        update(&mut self.enablement, name).map_err(|e| {
+            error!("Failed to save enablement state: {:#}", e);
+            format!("{e:#}")
+        })?;
+        info!(
+            "Service {} is now {:?}",
+            name,
+            self.enablement.state(name, autostart.unwrap_or(true))
+        );
+        Ok(())
+    }
+
+    /// Adds an instance of a template service, e.g. `getty@tty2` from `getty@`.
+    fn materialize_instance(&mut self, name: &str) -> Option<ServiceConfig> {
+        let Some((template_name, instance)) = template::split_instance(name) else {
+            if name.contains('@') {
+                warn!("Invalid instance name {}", name);
+            }
+            return None;
+        };
+        let config = template::instantiate(self.templates.get(template_name)?, instance);
+        info!("Instantiated {} from template {}", name, template_name);
+        self.states
+            .insert(name.to_string(), ServiceState::default());
+        self.configs.insert(name.to_string(), config.clone());
+        Some(config)
+    }
+
+    /// Re-reads the manifest and applies the difference to the loaded services.
+    fn reload_config(&mut self) -> Result<ConfigDiff> {
+        let mut config = SimaConfig::load(&self.manifest_path)?;
+        if self.entrypoint.is_some() {
+            config.reserve_entrypoint()?;
+        }
+        self.targets = std::mem::take(&mut config.targets);
+        self.signals = signals::default_table(!self.supervisor, config.default_target.as_deref());
+        self.signals.extend(std::mem::take(&mut config.signals));
+        if let Err(e) = self.listen() {
+            warn!("Failed to handle the configured signals: {:#}", e);
+        }
+        self.templates = config
+            .templates
+            .into_iter()
+            .map(|t| (t.name.clone(), t))
+            .collect();
+
+        // Instances started on demand are not in the manifest; keep them while their
+        // template still exists.
+        for name in self.configs.keys() {
+            if config.services.iter().any(|sc| &sc.name == name) {
+                continue;
+            }
+            if let Some((template_name, instance)) = template::split_instance(name)
+                && let Some(template) = self.templates.get(template_name)
+            {
+                config
+                    .services
+                    .push(template::instantiate(template, instance));
+            }
+        }
+
+        if let Some(entrypoint) = &self.entrypoint {
+            config.services.push(entrypoint.clone());
+        }
+
+        let mut diff = diff_configs(&self.configs, &config.services);
+        // Reported by an earlier reload; they are still running and dropped once they stop.
+        diff.removed
+            .retain(|name| !self.states.get(name).is_some_and(|state| state.removed));
+
+        for sc in config.services {
+            let state = self.states.entry(sc.name.clone()).or_default();
+            state.removed = false;
+            if diff.changed.contains(&sc.name) && state.status == ServiceStatus::Running {
+                state.needs_restart = true;
+            }
+            self.configs.insert(sc.name.clone(), sc);
+        }
+
+        for name in &diff.removed {
+            match self.states.get_mut(name) {
+                Some(state) if state.status == ServiceStatus::Running => {
+                    info!("Service {} removed, dropping it once it stops", name);
+                    state.removed = true;
+                }
+                _ => {
+                    self.configs.remove(name);
+                    self.states.remove(name);
+                }
+            }
+        }
+
+        // New services start as they would have at boot.
+        if !diff.added.is_empty() {
+            let wanted = self.boot_services();
+            for name in &diff.added {
+                if self.starts_at_boot(name, wanted.as_ref()) {
+                    self.start_service(name);
+                }
+            }
+        }
+
+        info!(
+            "Configuration reloaded: {} added, {} removed, {} changed",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        );
+        Ok(diff)
+    }
+
     fn reap_zombies(&mut self) {
         loop {
             match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
@@ -186,10 +751,18 @@ impl ServiceManager {
 
         if let Some(name) = self.pid_map.remove(&pid) {
             info!("Service {} (PID {}) exited: {:?}", name, pid, status);
+            if self.entrypoint.is_some() && name == ENTRYPOINT_SERVICE {
+                self.exit_code = Some(exit_code(status));
+            }
 
             if let Some(state) = self.states.get_mut(&name) {
                 state.pid = None;
                 state.status = ServiceStatus::Stopped;
+                if state.removed {
+                    info!("Dropping removed service {}", name);
+                    self.states.remove(&name);
+                    self.configs.remove(&name);
+                }
             }
         } else {
             info!("Reaped orphan process PID {} ({:?})", pid, status);
@@ -198,48 +771,224 @@ impl ServiceManager {
 
     async fn event_loop(&mut self) -> Result<()> {
         let (cmd_tx, mut cmd_rx) = mpsc::channel::<IpcCommand>(32);
-        let ipc_server = IpcServer::new()?;
+        let ipc_server = match self.ipc_server.take() {
+            Some(ipc_server) => ipc_server,
+            None => IpcServer::new(self.socket_path.as_deref())?,
+        };
 
         let mut sigchld = tokio_signal(SignalKind::child())?;
-        let mut sigterm = tokio_signal(SignalKind::terminate())?;
-        let mut sigint = tokio_signal(SignalKind::interrupt())?;
+
+        let (signal_tx, mut signal_rx) = mpsc::channel::<i32>(8);
+        self.signal_tx = Some(signal_tx);
+        self.listen()?;
 
         info!("Sima event loop started.");
+        // Children that exited before the SIGCHLD handler was installed.
+        self.reap_zombies();
 
         loop {
+            if let Some(code) = self.exit_code {
+                info!("Main command exited with status {}, shutting down...", code);
+                self.perform_shutdown(&mut sigchld).await;
+                break;
+            }
+
+            let wakeup = self.schedule.as_ref().map(|schedule| {
+                schedule
+                    .next_wakeup(SystemTime::now())
+                    .min(SCHEDULE_RECHECK)
+            });
+
             tokio::select! {
                 _ = sigchld.recv() => {
                     self.reap_zombies();
                 }
+                _ = sleep_until_due(wakeup) => {
+                    let Some(schedule) = &mut self.schedule else {
+                        continue;
+                    };
+                    if schedule.tick(SystemTime::now()) {
+                        let kind = schedule.shutdown.kind;
+                        self.schedule = None;
+                        info!("Scheduled {} is due", kind.verb());
+                        if self.shut_down(schedule::reboot_mode(kind), None, &mut sigchld).await {
+                            break;
+                        }
+                    }
+                }
                 result = ipc_server.accept() => {
                     if let Ok(stream) = result {
-                        if let Err(e) = handle_client(stream, &cmd_tx).await {
-                            error!("IPC client error: {}", e);
-                        }
+                        // Clients run on their own task: requests that wait for a reply
+                        // are answered by this loop.
+                        let cmd_tx = cmd_tx.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_client(stream, &cmd_tx).await {
+                                error!("IPC client error: {}", e);
+                            }
+                        });
                     }
                 }
                 Some(cmd) = cmd_rx.recv() => {
-                    if self.handle_ipc_command(cmd, &mut sigchld).await {
+                    if self.handle_ipc_command(cmd, &ipc_server, &mut sigchld).await {
                         break;
                     }
                 }
-                _ = sigterm.recv() => {
-                    info!("Received SIGTERM, shutting down...");
-                    self.perform_shutdown(&mut sigchld).await;
-                    break;
-                }
-                _ = sigint.recv() => {
-                    info!("Received SIGINT, shutting down...");
-                    self.perform_shutdown(&mut sigchld).await;
-                    break;
+                Some(signum) = signal_rx.recv() => {
+                    if self.handle_signal(signum, &mut sigchld).await {
+                        break;
+                    }
                 }
             }
         }
         Ok(())
     }
 
+    /// Starts relaying each signal of the action table, and those forwarded to the
+    /// entrypoint, to the event loop. Installing a handler overrides the default
+    /// disposition, so signals without an action keep theirs.
+    fn listen(&mut self) -> Result<()> {
+        let Some(signal_tx) = &self.signal_tx else {
+            return Ok(());
+        };
+        let forwarded: &[Signal] = if self.entrypoint.is_some() {
+            &FORWARDED_SIGNALS
+        } else {
+            &[]
+        };
+        let wanted: Vec<i32> = self
+            .signals
+            .keys()
+            .copied()
+            .chain(forwarded.iter().map(|&sig| sig as i32))
+            .collect();
+        for signum in wanted {
+            if self.listening.contains(&signum) {
+                continue;
+            }
+            let mut stream = tokio_signal(SignalKind::from_raw(signum))
+                .with_context(|| format!("failed to handle {}", signals::name(signum)))?;
+            let signal_tx = signal_tx.clone();
+            tokio::spawn(async move {
+                while stream.recv().await.is_some() {
+                    if signal_tx.send(signum).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            self.listening.insert(signum);
+        }
+        Ok(())
+    }
+
+    /// Forwards `signum` to the entrypoint or takes its action. Returns true if the event
+    /// loop should exit.
+    async fn handle_signal(&mut self, signum: i32, sigchld: &mut TokioSignal) -> bool {
+        if let Ok(sig) = Signal::try_from(signum)
+            && FORWARDED_SIGNALS.contains(&sig)
+            && self.forward_to_entrypoint(sig)
+        {
+            return false;
+        }
+        let name = signals::name(signum);
+        let Some(action) = self.signals.get(&signum).cloned() else {
+            info!("Received {}, which has no action any more", name);
+            return false;
+        };
+        info!("Received {}: {:?}", name, action);
+        match action {
+            SignalAction::Reboot => {
+                return self.shut_down(RebootMode::RB_AUTOBOOT, None, sigchld).await;
+            }
+            SignalAction::Poweroff => {
+                return self
+                    .shut_down(RebootMode::RB_POWER_OFF, None, sigchld)
+                    .await;
+            }
+            SignalAction::Halt => {
+                return self
+                    .shut_down(RebootMode::RB_HALT_SYSTEM, None, sigchld)
+                    .await;
+            }
+            SignalAction::Kexec => match self.check_kexec(&KexecImage::default()) {
+                Ok(()) => return self.shut_down(RebootMode::RB_KEXEC, None, sigchld).await,
+                Err(e) => error!("Not rebooting with kexec: {:#}", e),
+            },
+            SignalAction::Isolate(target) => {
+                if let Err(e) = self.isolate(&target) {
+                    error!("Failed to isolate target {}: {:#}", target, e);
+                }
+            }
+            SignalAction::ReopenLogs => {
+                if let Err(e) = Log::init(Some(self.log_dir.clone()), self.log_level) {
+                    warn!("Failed to reopen the log file: {:#}", e);
+                } else {
+                    info!("Log file reopened");
+                }
+            }
+            SignalAction::DumpState => self.dump_state(),
+            SignalAction::Ignore => {}
+        }
+        false
+    }
+
+    /// Logs the state of every service and mount.
+    fn dump_state(&self) {
+        info!(
+            "State dump: boot target {}, {} service(s) running",
+            self.boot_target.as_deref().unwrap_or("(none)"),
+            self.pid_map.len()
+        );
+        let mut units = self.get_status();
+        units.sort_by(|a, b| a.name.cmp(&b.name));
+        for unit in units {
+            let state = match (unit.running, unit.pid) {
+                (true, _) if unit.mount => "mounted".to_string(),
+                (true, Some(pid)) => format!("running (PID {pid})"),
+                (true, None) => "running".to_string(),
+                (false, _) => "stopped".to_string(),
+            };
+            let reason = unit
+                .failed
+                .map(|failed| format!(", failed: {failed}"))
+                .or(unit.skipped.map(|skipped| format!(", skipped: {skipped}")))
+                .unwrap_or_default();
+            let restart = if unit.needs_restart {
+                ", needs restart"
+            } else {
+                ""
+            };
+            info!(
+                "  {}: {}, {:?}{}{}",
+                unit.name, state, unit.enablement, restart, reason
+            );
+        }
+    }
+
+    /// Sends `sig` to the process group of the entrypoint. Returns false if there is no
+    /// entrypoint running.
+    fn forward_to_entrypoint(&self, sig: Signal) -> bool {
+        if self.entrypoint.is_none() {
+            return false;
+        }
+        let Some(pid) = self.states.get(ENTRYPOINT_SERVICE).and_then(|s| s.pid) else {
+            return false;
+        };
+        info!("Forwarding {} to the main command (PID {})", sig, pid);
+        if let Err(e) = signal::kill(Pid::from_raw(-pid.as_raw()), sig)
+            && e != nix::Error::ESRCH
+        {
+            warn!("Failed to forward {} to the main command: {}", sig, e);
+        }
+        true
+    }
+
     /// Returns true if event loop should exit
-    async fn handle_ipc_command(&mut self, cmd: IpcCommand, sigchld: &mut TokioSignal) -> bool {
+    async fn handle_ipc_command(
+        &mut self,
+        cmd: IpcCommand,
+        ipc_server: &IpcServer,
+        sigchld: &mut TokioSignal,
+    ) -> bool {
         match cmd {
             IpcCommand::Start(name) => {
                 self.start_service(&name);
@@ -254,20 +1003,134 @@ impl ServiceManager {
                 false
             }
             IpcCommand::Status(tx) => {
-                let _ = tx.send(self.get_status());
+                let shutdown = self.schedule.as_ref().map(|s| s.shutdown.clone());
+                let _ = tx.send((self.get_status(), shutdown));
+                false
+            }
+            IpcCommand::ScheduleShutdown(shutdown, tx) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                if shutdown.at <= now {
+                    let _ = tx.send(Err("the shutdown time has passed".to_string()));
+                    return false;
+                }
+                if let Some(previous) = self.schedule.take() {
+                    previous.cancel();
+                }
+                self.schedule = Some(Schedule::new(shutdown, !self.supervisor));
+                let _ = tx.send(Ok(()));
+                false
+            }
+            IpcCommand::CancelShutdown(tx) => {
+                let result = match self.schedule.take() {
+                    Some(schedule) => {
+                        schedule.cancel();
+                        Ok(())
+                    }
+                    None => Err("no shutdown is scheduled".to_string()),
+                };
+                let _ = tx.send(result);
+                false
+            }
+            IpcCommand::ReloadConfig(tx) => {
+                info!("Config reload requested via IPC");
+                let result = self.reload_config().map_err(|e| {
+                    error!("Failed to reload config: {:#}", e);
+                    format!("{e:#}")
+                });
+                let _ = tx.send(result);
+                false
+            }
+            IpcCommand::Cat(name, tx) => {
+                let result =
+                    SimaConfig::describe(&self.manifest_path, &name).map_err(|e| format!("{e:#}"));
+                let _ = tx.send(result);
+                false
+            }
+            IpcCommand::Enable(name, tx) => {
+                let _ = tx.send(self.update_enablement(&name, true, EnablementStore::enable));
+                false
+            }
+            IpcCommand::Disable(name, tx) => {
+                let _ = tx.send(self.update_enablement(&name, true, EnablementStore::disable));
+                false
+            }
+            IpcCommand::Mask(name, tx) => {
+                let _ = tx.send(self.update_enablement(&name, false, EnablementStore::mask));
+                false
+            }
+            IpcCommand::Unmask(name, tx) => {
+                let _ = tx.send(self.update_enablement(&name, false, EnablementStore::unmask));
+                false
+            }
+            IpcCommand::Isolate(target, tx) => {
+                let result = self.isolate(&target).map_err(|e| {
+                    error!("Failed to isolate target {}: {:#}", target, e);
+                    format!("{e:#}")
+                });
+                let _ = tx.send(result);
                 false
             }
             IpcCommand::Poweroff => {
                 info!("Poweroff requested via IPC");
-                self.perform_shutdown(sigchld).await;
-                let _ = reboot(RebootMode::RB_POWER_OFF);
-                true
+                self.shut_down(RebootMode::RB_POWER_OFF, None, sigchld)
+                    .await
             }
-            IpcCommand::Reboot => {
+            IpcCommand::Reboot(arg, tx) => {
+                let arg = match self.check_reboot_arg(arg) {
+                    Ok(arg) => arg,
+                    Err(e) => {
+                        error!("Not rebooting: {:#}", e);
+                        let _ = tx.send(Err(format!("{e:#}")));
+                        return false;
+                    }
+                };
                 info!("Reboot requested via IPC");
-                self.perform_shutdown(sigchld).await;
-                let _ = reboot(RebootMode::RB_AUTOBOOT);
-                true
+                let _ = tx.send(Ok(()));
+                self.shut_down(RebootMode::RB_AUTOBOOT, arg.as_deref(), sigchld)
+                    .await
+            }
+            IpcCommand::Halt => {
+                info!("Halt requested via IPC");
+                self.shut_down(RebootMode::RB_HALT_SYSTEM, None, sigchld)
+                    .await
+            }
+            IpcCommand::Kexec(image, tx) => {
+                if let Err(e) = self.check_kexec(&image) {
+                    error!("Not rebooting with kexec: {:#}", e);
+                    let _ = tx.send(Err(format!("{e:#}")));
+                    return false;
+                }
+                info!("Kexec requested via IPC");
+                let _ = tx.send(Ok(()));
+                self.shut_down(RebootMode::RB_KEXEC, None, sigchld).await
+            }
+            IpcCommand::DaemonReexec(tx) => {
+                if let Err(e) = self.check_reexec() {
+                    error!("Not re-executing: {:#}", e);
+                    let _ = tx.send(Err(format!("{e:#}")));
+                    return false;
+                }
+                info!(
+                    "Re-executing sima-init, keeping {} service(s) running",
+                    self.pid_map.len()
+                );
+                let (written_tx, written_rx) = tokio::sync::oneshot::channel();
+                if tx.send(Ok(written_tx)).is_ok() {
+                    // Once written, the reply waits in the client's socket after we exec.
+                    let _ = timeout(REPLY_TIMEOUT, written_rx).await;
+                }
+                let mut state = self.save_state();
+                state.listener = ipc_server
+                    .hand_over()
+                    .map_err(|e| warn!("The new sima-init will bind the IPC socket again: {:#}", e))
+                    .ok();
+                let Err(e) = reexec::exec(&state);
+                error!("Failed to re-execute: {:#}", e);
+                ipc_server.take_back();
+                false
             }
             IpcCommand::SoftReboot => {
                 info!("Soft-reboot requested via IPC");
@@ -277,9 +1140,62 @@ impl ServiceManager {
         }
     }
 
+    /// Stops every service and asks the kernel to reboot as `mode` says, with `arg` for
+    /// the firmware or bootloader. A supervisor exits instead, or restarts itself for a
+    /// reboot. Returns true if the event loop should exit, which PID 1 must never do.
+    async fn shut_down(
+        &mut self,
+        mode: RebootMode,
+        arg: Option<&CStr>,
+        sigchld: &mut TokioSignal,
+    ) -> bool {
+        self.perform_shutdown(sigchld).await;
+        if self.supervisor {
+            if mode == RebootMode::RB_AUTOBOOT {
+                // Restarting the supervisor is the closest thing to a reboot.
+                self.exec_self();
+            }
+            return true;
+        }
+        let Err(e) = shutdown::finalize(mode, arg);
+        error!("Failed to shut down ({:?}): {}", mode, e);
+        false
+    }
+
+    /// Checks the argument for a reboot before anything is stopped. Only the kernel can
+    /// pass one on, so a supervisor refuses it.
+    fn check_reboot_arg(&self, arg: Option<String>) -> Result<Option<CString>> {
+        let Some(arg) = arg else {
+            return Ok(None);
+        };
+        if self.supervisor {
+            bail!("a supervisor cannot pass `{arg}` to the firmware or bootloader");
+        }
+        CString::new(arg)
+            .map(Some)
+            .context("the reboot argument contains a NUL byte")
+    }
+
+    /// Loads the kernel for a kexec reboot, before anything is stopped so that a failure
+    /// leaves the system running.
+    fn check_kexec(&self, image: &KexecImage) -> Result<()> {
+        if self.supervisor {
+            bail!("a supervisor cannot boot another kernel");
+        }
+        shutdown::load_kernel(image)
+    }
+
+    /// Makes sure the new sima-init will find its binary and a manifest it can load, as
+    /// there is no going back after exec.
+    fn check_reexec(&self) -> Result<()> {
+        reexec::check_binary(&reexec::executable()?)?;
+        SimaConfig::load(&self.manifest_path)?;
+        Ok(())
+    }
+
     fn exec_self(&self) -> ! {
         let exe = std::env::current_exe().unwrap_or_else(|_| "/sbin/sima-init".into());
-        let err = Command::new(&exe).exec();
+        let err = Command::new(&exe).args(reexec::fresh_arguments()).exec();
         panic!("soft-reboot exec failed: {}", err);
     }
 
@@ -322,7 +1238,70 @@ impl ServiceManager {
     }
 }
 
-fn parse_environment(environment: &[String]) -> Result<Vec<(String, String)>> {
+/// Waits `wakeup`, or forever without one.
+async fn sleep_until_due(wakeup: Option<Duration>) {
+    match wakeup {
+        Some(wakeup) => tokio::time::sleep(wakeup).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A service as named in progress lines: its description, if any, after its name.
+fn describe(name: &str, config: &ServiceConfig) -> String {
+    match &config.description {
+        Some(description) => format!("{name} - {description}"),
+        None => name.to_string(),
+    }
+}
+
+/// Makes the entrypoint's process group the foreground group of our terminal so that an
+/// interactive command can read from it. Only done while we are in the foreground.
+fn give_terminal(pgid: Pid) {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() || tcgetpgrp(&stdin) != Ok(getpgrp()) {
+        return;
+    }
+    if let Err(e) = tcsetpgrp(&stdin, pgid) {
+        warn!("Failed to hand the terminal to the main command: {}", e);
+        return;
+    }
+    // It may already have been stopped by reading from the terminal too early.
+    let _ = signal::kill(Pid::from_raw(-pgid.as_raw()), Signal::SIGCONT);
+}
+
+/// The exit status to report for the entrypoint, following the shell convention of
+/// 128 + the signal number for a command killed by a signal.
+fn exit_code(status: WaitStatus) -> i32 {
+    match status {
+        WaitStatus::Exited(_, code) => code,
+        WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+        _ => 1,
+    }
+}
+
+/// Compares the loaded definitions with freshly loaded ones; each list is sorted.
+fn diff_configs(current: &HashMap<String, ServiceConfig>, loaded: &[ServiceConfig]) -> ConfigDiff {
+    let mut diff = ConfigDiff::default();
+    for sc in loaded {
+        match current.get(&sc.name) {
+            None => diff.added.push(sc.name.clone()),
+            Some(existing) if existing != sc => diff.changed.push(sc.name.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.removed = current
+        .keys()
+        .filter(|name| !loaded.iter().any(|sc| &sc.name == *name))
+        .cloned()
+        .collect();
+
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    diff
+}
+
+pub fn parse_environment(environment: &[String]) -> Result<Vec<(String, String)>> {
     environment
         .iter()
         .map(|entry| {
@@ -341,7 +1320,53 @@ fn parse_environment(environment: &[String]) -> Result<Vec<(String, String)>> {
 
 #[cfg(test)]
 mod tests {
-    use super::parse_environment;
+    use super::{ServiceManager, ServiceStatus, diff_configs, parse_environment};
+    use crate::config::{ServiceConfig, SimaConfig};
+    use crate::mounts::MountTable;
+    use crate::options::Options;
+    use crate::reexec::SavedState;
+    use crate::testutil::{scratch_dir, write_service};
+    use nix::sys::signal::{self, Signal};
+    use nix::sys::wait::waitpid;
+    use nix::unistd::Pid;
+    use sima_proto::KexecImage;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    fn service(name: &str, cmdline: &str) -> ServiceConfig {
+        serde_yaml::from_str(&format!("name: {name}\ncmdline: {cmdline}\n"))
+            .expect("service config should parse")
+    }
+
+    /// A supervisor for the manifest `root/sima.yml`, keeping its state under `root`.
+    fn manager(root: &Path) -> ServiceManager {
+        let manifest = root.join("sima.yml");
+        let options = Options::from_args(&[
+            "sima-init",
+            "--supervisor",
+            "--config",
+            &manifest.display().to_string(),
+            "--state-dir",
+            &root.join("state").display().to_string(),
+            "--log-dir",
+            &root.join("log").display().to_string(),
+        ]);
+        let config = SimaConfig::load(&manifest).expect("config should load");
+        ServiceManager::new(config, &options, MountTable::default())
+    }
+
+    fn pid(manager: &ServiceManager, name: &str) -> Option<Pid> {
+        manager.states.get(name).and_then(|state| state.pid)
+    }
+
+    /// Kills the services `manager` started and waits for them.
+    fn kill_all(manager: &ServiceManager) {
+        for pid in manager.pid_map.keys() {
+            let _ = signal::kill(Pid::from_raw(-pid.as_raw()), Signal::SIGKILL);
+            let _ = waitpid(*pid, None);
+        }
+    }
 
     #[test]
     fn parse_environment_supports_multiple_variables() {
@@ -374,4 +1399,172 @@ mod tests {
         let err = parse_environment(&["=value".to_string()]).expect_err("parse should fail");
         assert!(err.to_string().contains("variable name cannot be empty"));
     }
+
+    #[test]
+    fn diff_configs_reports_added_removed_and_changed() {
+        let current: HashMap<_, _> = [
+            service("kept", "/bin/kept"),
+            service("changed", "/bin/old"),
+            service("removed", "/bin/removed"),
+        ]
+        .into_iter()
+        .map(|sc| (sc.name.clone(), sc))
+        .collect();
+        let loaded = vec![
+            service("kept", "/bin/kept"),
+            service("changed", "/bin/new"),
+            service("added", "/bin/added"),
+        ];
+
+        let diff = diff_configs(&current, &loaded);
+
+        assert_eq!(diff.added, vec!["added".to_string()]);
+        assert_eq!(diff.removed, vec!["removed".to_string()]);
+        assert_eq!(diff.changed, vec!["changed".to_string()]);
+    }
+
+    #[test]
+    fn reload_starts_added_services_and_reports_removals_once() {
+        let root = scratch_dir("reload");
+        write_service(&root, "a.yml", "a", "/bin/sleep 30");
+        write_service(&root, "b.yml", "b", "/bin/sleep 30");
+        fs::write(
+            root.join("c.yml"),
+            "name: c\ncmdline: /bin/sleep 30\nautostart: false\n",
+        )
+        .expect("service should be written");
+        let manifest = root.join("sima.yml");
+        let list = |files: &[&str]| {
+            let paths: Vec<String> = files
+                .iter()
+                .map(|file| format!("'{}'", root.join(file).display()))
+                .collect();
+            format!("services: [{}]\n", paths.join(", "))
+        };
+        fs::write(&manifest, list(&["a.yml"])).expect("manifest should be written");
+        let mut manager = manager(&root);
+
+        fs::write(&manifest, list(&["a.yml", "b.yml", "c.yml"]))
+            .expect("manifest should be written");
+        let diff = manager.reload_config().expect("reload should succeed");
+        assert_eq!(diff.added, vec!["b".to_string(), "c".to_string()]);
+        assert!(pid(&manager, "b").is_some());
+        assert!(pid(&manager, "c").is_none());
+
+        fs::write(&manifest, list(&["a.yml"])).expect("manifest should be written");
+        let diff = manager.reload_config().expect("reload should succeed");
+        assert_eq!(diff.removed, vec!["b".to_string(), "c".to_string()]);
+        assert_eq!(manager.states["b"].status, ServiceStatus::Running);
+        let diff = manager.reload_config().expect("reload should succeed");
+        assert!(diff.removed.is_empty());
+
+        kill_all(&manager);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn targets_start_their_services_whatever_autostart_says() {
+        let root = scratch_dir("isolate");
+        write_service(&root, "b.yml", "b", "/bin/sleep 30");
+        for name in ["a", "c"] {
+            fs::write(
+                root.join(format!("{name}.yml")),
+                format!("name: {name}\ncmdline: /bin/sleep 30\nautostart: false\n"),
+            )
+            .expect("service should be written");
+        }
+        let files: Vec<String> = ["a.yml", "b.yml", "c.yml"]
+            .iter()
+            .map(|file| format!("'{}'", root.join(file).display()))
+            .collect();
+        fs::write(
+            root.join("sima.yml"),
+            format!(
+                "services: [{}]\ndefault_target: base\ntargets:\n  base:\n    services: [a]\n  maint:\n    services: [b, c]\n",
+                files.join(", ")
+            ),
+        )
+        .expect("manifest should be written");
+        let mut manager = manager(&root);
+        manager.enablement.mask_runtime("c");
+
+        manager.start_boot_services();
+        assert!(pid(&manager, "a").is_some());
+        assert!(pid(&manager, "b").is_none());
+
+        manager.isolate("maint").expect("target should resolve");
+        assert!(pid(&manager, "b").is_some());
+        assert!(pid(&manager, "c").is_none());
+
+        kill_all(&manager);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn supervisor_refuses_a_reboot_argument_before_stopping_anything() {
+        let root = scratch_dir("reboot-arg");
+        fs::write(root.join("sima.yml"), "services: []\n").expect("manifest should be written");
+        let manager = manager(&root);
+
+        assert_eq!(
+            manager
+                .check_reboot_arg(None)
+                .expect("a plain reboot is fine"),
+            None
+        );
+        let err = manager
+            .check_reboot_arg(Some("bootloader".into()))
+            .expect_err("a supervisor should refuse the argument");
+        assert!(err.to_string().contains("supervisor"));
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn supervisor_refuses_kexec_before_stopping_anything() {
+        let root = scratch_dir("kexec");
+        fs::write(root.join("sima.yml"), "services: []\n").expect("manifest should be written");
+        let manager = manager(&root);
+
+        let err = manager
+            .check_kexec(&KexecImage::default())
+            .expect_err("a supervisor should refuse kexec");
+        assert!(err.to_string().contains("supervisor"));
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn restore_takes_over_the_saved_services() {
+        let root = scratch_dir("restore");
+        write_service(&root, "a.yml", "a", "/bin/sleep 30");
+        write_service(&root, "b.yml", "b", "/bin/sleep 30");
+        let files: Vec<String> = ["a.yml", "b.yml"]
+            .iter()
+            .map(|file| format!("'{}'", root.join(file).display()))
+            .collect();
+        fs::write(
+            root.join("sima.yml"),
+            format!("services: [{}]\n", files.join(", ")),
+        )
+        .expect("manifest should be written");
+        let mut old = manager(&root);
+        old.enablement.mask_runtime("b");
+        old.start_boot_services();
+        let running = pid(&old, "a").expect("a should be running");
+
+        // Through JSON, as across exec.
+        let json = serde_json::to_string(&old.save_state()).expect("state should serialize");
+        let saved: SavedState = serde_json::from_str(&json).expect("state should parse");
+        let mut new = manager(&root);
+        new.restore(saved);
+
+        assert!(new.restored);
+        assert_eq!(pid(&new, "a"), Some(running));
+        assert_eq!(new.pid_map.get(&running).map(String::as_str), Some("a"));
+        assert_eq!(new.states["a"].status, ServiceStatus::Running);
+        assert!(pid(&new, "b").is_none());
+        assert!(new.enablement.is_masked("b"));
+
+        kill_all(&new);
+        let _ = fs::remove_dir_all(root);
+    }
 }
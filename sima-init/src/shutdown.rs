@@ -0,0 +1,130 @@
+use crate::logger::Log;
+use crate::mounts;
+use crate::sys;
+use anyhow::{Context, Result, bail};
+use nix::errno::Errno;
+use nix::sys::reboot::{RebootMode, reboot};
+use nix::sys::signal::{Signal, kill};
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+use nix::unistd::Pid;
+use sima_proto::KexecImage;
+use spdlog::{info, warn};
+use std::convert::Infallible;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// How long processes left after the services have stopped get to exit on SIGTERM.
+pub const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const KEXEC_LOADED: &str = "/sys/kernel/kexec_loaded";
+const PROC_CMDLINE: &str = "/proc/cmdline";
+
+/// The last stage of shutting down, once the services are stopped: terminates every
+/// remaining process, disables swap, unmounts file systems, syncs and hands `mode` to
+/// the kernel, with `arg` for the firmware or bootloader if `mode` is `RB_AUTOBOOT`.
+/// Only returns if `reboot(2)` fails.
+pub fn finalize(mode: RebootMode, arg: Option<&CStr>) -> nix::Result<Infallible> {
+    kill_all(Signal::SIGTERM);
+    if !wait_for_processes(KILL_TIMEOUT) {
+        warn!("Processes left after {:?}, killing them", KILL_TIMEOUT);
+        kill_all(Signal::SIGKILL);
+        wait_for_processes(KILL_TIMEOUT);
+    }
+
+    swapoff();
+    // The log file would keep its file system busy.
+    if let Err(e) = Log::close_file() {
+        warn!("Failed to close the log file: {:#}", e);
+    }
+    mounts::unmount_all();
+    match arg {
+        Some(arg) if mode == RebootMode::RB_AUTOBOOT => {
+            info!("Issuing {:?} with {:?}", mode, arg);
+            spdlog::default_logger().flush();
+            sys::reboot_with_arg(arg)
+        }
+        _ => {
+            info!("Issuing {:?}", mode);
+            spdlog::default_logger().flush();
+            reboot(mode)
+        }
+    }
+}
+
+/// Loads the kernel of `image` so that `RB_KEXEC` boots it, with kexec_file_load(2).
+/// Without a kernel in `image`, one must already be loaded.
+pub fn load_kernel(image: &KexecImage) -> Result<()> {
+    let Some(kernel) = &image.kernel else {
+        if fs::read_to_string(KEXEC_LOADED).is_ok_and(|loaded| loaded.trim() == "1") {
+            return Ok(());
+        }
+        bail!("no kernel is loaded for kexec; pass one with --kernel");
+    };
+
+    let kernel_file =
+        File::open(kernel).with_context(|| format!("failed to open {}", kernel.display()))?;
+    let initrd = match &image.initrd {
+        Some(initrd) => Some(
+            File::open(initrd).with_context(|| format!("failed to open {}", initrd.display()))?,
+        ),
+        None => None,
+    };
+    let cmdline = match &image.cmdline {
+        Some(cmdline) => cmdline.clone(),
+        None => fs::read_to_string(PROC_CMDLINE)
+            .with_context(|| format!("failed to read {PROC_CMDLINE}"))?
+            .trim_end()
+            .to_owned(),
+    };
+    let cmdline = CString::new(cmdline).context("the kernel command line contains a NUL byte")?;
+    sys::kexec_file_load(&kernel_file, initrd.as_ref(), &cmdline)
+        .with_context(|| format!("failed to load {}", kernel.display()))?;
+    info!("Loaded {} for kexec", kernel.display());
+    Ok(())
+}
+
+/// Sends `sig` to every process but init itself.
+fn kill_all(sig: Signal) {
+    info!("Sending {} to all remaining processes", sig);
+    if let Err(e) = kill(Pid::from_raw(-1), sig)
+        && e != Errno::ESRCH
+    {
+        warn!("Failed to send {} to all processes: {}", sig, e);
+    }
+}
+
+/// Reaps exiting processes, which are all children of init by now, until none is left.
+/// Returns false if some are still running after `timeout`.
+pub fn wait_for_processes(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Err(Errno::ECHILD) => return true,
+            Ok(WaitStatus::StillAlive) | Err(_) => {
+                if Instant::now() >= deadline {
+                    return false;
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Turns off swap so that no file system holds an active swap file and no swapped-out
+/// page is lost. Left to `swapoff(8)`, which knows every kind of swap area.
+fn swapoff() {
+    let swaps = fs::read_to_string("/proc/swaps").unwrap_or_default();
+    // The first line is a header.
+    if swaps.lines().count() <= 1 {
+        return;
+    }
+    info!("Disabling swap");
+    match Command::new("swapoff").arg("-a").status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("swapoff -a failed ({})", status),
+        Err(e) => warn!("Failed to run swapoff: {}", e),
+    }
+}
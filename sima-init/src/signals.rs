@@ -0,0 +1,161 @@
+use crate::options::RESCUE_TARGET;
+use anyhow::{Result, bail};
+use nix::libc;
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// What sima does when it receives a signal, as set in the manifest's `signals` table.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SignalAction {
+    /// Stop every service and reboot.
+    Reboot,
+    /// Stop every service and power off.
+    Poweroff,
+    /// Stop every service and halt without powering off.
+    Halt,
+    /// Stop every service and boot the kernel loaded with `kexec -l`.
+    Kexec,
+    /// Start the services of a target and stop every other service.
+    Isolate(String),
+    /// Reopen the log file, e.g. after it was rotated.
+    ReopenLogs,
+    /// Log the state of every service and mount.
+    DumpState,
+    /// Do nothing.
+    Ignore,
+}
+
+/// Signal numbers mapped to their actions.
+pub type SignalTable = BTreeMap<i32, SignalAction>;
+
+/// The actions taken without a `signals` table, following the conventions of other init
+/// systems: SIGINT is what the kernel sends for Ctrl-Alt-Del, SIGPWR reports a power
+/// failure and SIGRTMIN+n select a target or shutdown type as in systemd.
+///
+/// A supervisor shuts down on SIGTERM and SIGINT, as any daemon would. Init ignores
+/// SIGTERM, which a stray `kill 1` would otherwise turn into a power off.
+pub fn default_table(init: bool, default_target: Option<&str>) -> SignalTable {
+    let mut table = SignalTable::from([
+        (Signal::SIGUSR1 as i32, SignalAction::ReopenLogs),
+        (Signal::SIGUSR2 as i32, SignalAction::DumpState),
+    ]);
+    if !init {
+        table.insert(Signal::SIGTERM as i32, SignalAction::Poweroff);
+        table.insert(Signal::SIGINT as i32, SignalAction::Poweroff);
+        return table;
+    }
+
+    table.insert(Signal::SIGTERM as i32, SignalAction::Ignore);
+    table.insert(Signal::SIGINT as i32, SignalAction::Reboot);
+    table.insert(Signal::SIGPWR as i32, SignalAction::Poweroff);
+    if let Some(target) = default_target {
+        table.insert(rtmin(0), SignalAction::Isolate(target.to_string()));
+    }
+    table.insert(rtmin(1), SignalAction::Isolate(RESCUE_TARGET.into()));
+    table.insert(rtmin(3), SignalAction::Halt);
+    table.insert(rtmin(4), SignalAction::Poweroff);
+    table.insert(rtmin(5), SignalAction::Reboot);
+    table.insert(rtmin(6), SignalAction::Kexec);
+    table
+}
+
+fn rtmin(offset: i32) -> i32 {
+    libc::SIGRTMIN() + offset
+}
+
+/// Parses a signal name such as `SIGPWR`, `PWR` or `SIGRTMIN+4`.
+pub fn parse(name: &str) -> Result<i32> {
+    let name = name.trim();
+    let bare = name.strip_prefix("SIG").unwrap_or(name);
+    if let Some(offset) = bare.strip_prefix("RTMIN") {
+        let offset: i32 = match offset.strip_prefix('+') {
+            Some(offset) => offset.parse().map_err(|_| invalid(name))?,
+            None if offset.is_empty() => 0,
+            None => return Err(invalid(name)),
+        };
+        if rtmin(offset) > libc::SIGRTMAX() {
+            bail!("signal `{name}` is past SIGRTMAX");
+        }
+        return Ok(rtmin(offset));
+    }
+
+    let signal: Signal = format!("SIG{bare}").parse().map_err(|_| invalid(name))?;
+    if matches!(
+        signal,
+        Signal::SIGKILL
+            | Signal::SIGSTOP
+            | Signal::SIGCHLD
+            | Signal::SIGSEGV
+            | Signal::SIGILL
+            | Signal::SIGFPE
+            | Signal::SIGBUS
+    ) {
+        bail!("signal `{name}` cannot be configured");
+    }
+    Ok(signal as i32)
+}
+
+fn invalid(name: &str) -> anyhow::Error {
+    anyhow::anyhow!("unknown signal `{name}`")
+}
+
+/// The name of signal `signum`, e.g. `SIGTERM` or `SIGRTMIN+4`.
+pub fn name(signum: i32) -> String {
+    match Signal::try_from(signum) {
+        Ok(signal) => signal.as_str().to_string(),
+        Err(_) if signum >= libc::SIGRTMIN() => format!("SIGRTMIN+{}", signum - libc::SIGRTMIN()),
+        Err(_) => format!("signal {signum}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_accepts_names_with_and_without_prefix() {
+        assert_eq!(
+            parse("SIGPWR").expect("SIGPWR should parse"),
+            Signal::SIGPWR as i32
+        );
+        assert_eq!(
+            parse("HUP").expect("HUP should parse"),
+            Signal::SIGHUP as i32
+        );
+        let rtmin4 = parse("SIGRTMIN+4").expect("SIGRTMIN+4 should parse");
+        assert_eq!(rtmin4, libc::SIGRTMIN() + 4);
+        assert_eq!(
+            parse("RTMIN").expect("RTMIN should parse"),
+            libc::SIGRTMIN()
+        );
+        assert_eq!(name(rtmin4), "SIGRTMIN+4");
+        assert!(parse("SIGNOPE").is_err());
+        assert!(parse("SIGRTMIN-1").is_err());
+        assert!(parse("SIGRTMIN+100").is_err());
+        assert!(parse("SIGKILL").is_err());
+    }
+
+    #[test]
+    fn default_table_only_shuts_down_init_on_request() {
+        let init = default_table(true, Some("multi-user"));
+        assert_eq!(init[&(Signal::SIGTERM as i32)], SignalAction::Ignore);
+        assert_eq!(init[&(Signal::SIGINT as i32)], SignalAction::Reboot);
+        assert_eq!(init[&(Signal::SIGPWR as i32)], SignalAction::Poweroff);
+        assert_eq!(
+            init[&rtmin(0)],
+            SignalAction::Isolate("multi-user".to_string())
+        );
+        assert_eq!(init[&rtmin(3)], SignalAction::Halt);
+        assert_eq!(init[&rtmin(6)], SignalAction::Kexec);
+
+        let supervisor = default_table(false, None);
+        assert_eq!(
+            supervisor[&(Signal::SIGTERM as i32)],
+            SignalAction::Poweroff
+        );
+        assert_eq!(supervisor[&(Signal::SIGINT as i32)], SignalAction::Poweroff);
+        assert!(!supervisor.contains_key(&(Signal::SIGPWR as i32)));
+    }
+}
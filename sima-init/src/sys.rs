@@ -0,0 +1,82 @@
+//! System calls that neither std nor nix wrap, and taking ownership of an inherited
+//! descriptor. This is the only module allowed to use unsafe code; each wrapper checks
+//! what it can and documents what it relies on.
+#![allow(unsafe_code)]
+
+use nix::errno::Errno;
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use nix::libc;
+use nix::sys::stat::{SFlag, fstat};
+use std::convert::Infallible;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+/// Reboots like `RB_AUTOBOOT`, passing `arg` to the firmware or bootloader with
+/// `LINUX_REBOOT_CMD_RESTART2`. Only returns if reboot(2) fails.
+pub fn reboot_with_arg(arg: &CStr) -> nix::Result<Infallible> {
+    // SAFETY: the magic numbers select RESTART2, which reads a NUL-terminated string
+    // from the last argument, and `arg` is one that outlives the call.
+    unsafe {
+        libc::syscall(
+            libc::SYS_reboot,
+            libc::LINUX_REBOOT_MAGIC1,
+            libc::LINUX_REBOOT_MAGIC2,
+            libc::LINUX_REBOOT_CMD_RESTART2,
+            arg.as_ptr(),
+        );
+    }
+    Err(Errno::last())
+}
+
+/// Loads `kernel`, with `initrd` if any, to be booted by `RB_KEXEC` with `cmdline`,
+/// using kexec_file_load(2).
+pub fn kexec_file_load(kernel: &File, initrd: Option<&File>, cmdline: &CStr) -> nix::Result<()> {
+    let (initrd_fd, flags) = match initrd {
+        Some(initrd) => (initrd.as_raw_fd(), 0),
+        None => (-1, libc::KEXEC_FILE_NO_INITRAMFS),
+    };
+    let cmdline = cmdline.to_bytes_with_nul();
+    // SAFETY: both descriptors are open, or the initrd one is ignored as the flags say,
+    // and the kernel reads exactly `cmdline.len()` bytes, the NUL included, from it.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_kexec_file_load,
+            kernel.as_raw_fd(),
+            initrd_fd,
+            cmdline.len(),
+            cmdline.as_ptr(),
+            flags as libc::c_ulong,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+/// Takes ownership of the listening socket `fd`, inherited across exec, and closes it
+/// on exec again. Nothing else in the process may own `fd`: sima-init only adopts the
+/// descriptor named in the state it was handed, once.
+pub fn adopt_listener(fd: RawFd) -> io::Result<UnixListener> {
+    if fd <= libc::STDERR_FILENO {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("fd {fd} is a standard stream"),
+        ));
+    }
+    // SAFETY: F_GETFD only checks that `fd` is open.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is open and, as documented, owned by nothing else.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let stat = fstat(&fd)?;
+    if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFSOCK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("fd {} is not a socket", fd.as_raw_fd()),
+        ));
+    }
+    fcntl(&fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    Ok(UnixListener::from(fd))
+}
@@ -0,0 +1,126 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A named group of services, such as `rescue` or `multi-user`, defined in the manifest.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct TargetConfig {
+    pub description: Option<String>,
+    /// Other targets whose services are part of this one.
+    #[serde(default)]
+    pub requires: Vec<String>,
+    #[serde(default)]
+    pub services: Vec<String>,
+}
+
+pub type Targets = BTreeMap<String, TargetConfig>;
+
+/// The services wanted by target `name`, including those of every target it requires.
+pub fn resolve(targets: &Targets, name: &str) -> Result<BTreeSet<String>> {
+    let mut services = BTreeSet::new();
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![name];
+
+    while let Some(current) = pending.pop() {
+        if !visited.insert(current) {
+            continue;
+        }
+        let Some(target) = targets.get(current) else {
+            bail!("target `{current}` is not defined");
+        };
+        services.extend(target.services.iter().cloned());
+        pending.extend(target.requires.iter().map(String::as_str));
+    }
+    Ok(services)
+}
+
+/// The cycles formed by `requires`, each as the targets along it with the first one
+/// repeated at the end, e.g. `[a, b, a]`. Each cycle is reported once.
+pub fn cycles(targets: &Targets) -> Vec<Vec<String>> {
+    let mut cycles = Vec::new();
+    let mut done = BTreeSet::new();
+    for name in targets.keys() {
+        let mut stack = Vec::new();
+        find_cycles(targets, name, &mut stack, &mut done, &mut cycles);
+    }
+    cycles
+}
+
+fn find_cycles<'a>(
+    targets: &'a Targets,
+    name: &'a str,
+    stack: &mut Vec<&'a str>,
+    done: &mut BTreeSet<&'a str>,
+    cycles: &mut Vec<Vec<String>>,
+) {
+    if let Some(start) = stack.iter().position(|&n| n == name) {
+        let mut cycle: Vec<String> = stack[start..].iter().map(|n| n.to_string()).collect();
+        cycle.push(name.to_string());
+        cycles.push(cycle);
+        return;
+    }
+    if done.contains(name) {
+        return;
+    }
+    stack.push(name);
+    for required in targets.get(name).into_iter().flat_map(|t| &t.requires) {
+        find_cycles(targets, required, stack, done, cycles);
+    }
+    stack.pop();
+    done.insert(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Targets, cycles, resolve};
+
+    #[test]
+    fn resolve_follows_requires() {
+        let targets: Targets = serde_yaml::from_str(
+            r#"
+rescue:
+  services: [getty@tty1]
+multi-user:
+  requires: [rescue, graphical]
+  services: [sshd]
+graphical:
+  requires: [multi-user]
+  services: [kiosk]
+"#,
+        )
+        .expect("targets should parse");
+
+        let services: Vec<_> = resolve(&targets, "multi-user")
+            .expect("target should resolve")
+            .into_iter()
+            .collect();
+        assert_eq!(services, vec!["getty@tty1", "kiosk", "sshd"]);
+
+        let err = resolve(&targets, "maintenance").expect_err("unknown target should fail");
+        assert!(err.to_string().contains("`maintenance` is not defined"));
+    }
+
+    #[test]
+    fn cycles_reports_each_requires_loop_once() {
+        let targets: Targets = serde_yaml::from_str(
+            r#"
+rescue: {}
+multi-user:
+  requires: [rescue, graphical]
+graphical:
+  requires: [multi-user]
+loop:
+  requires: [loop]
+"#,
+        )
+        .expect("targets should parse");
+
+        assert_eq!(
+            cycles(&targets),
+            vec![
+                vec!["graphical", "multi-user", "graphical"],
+                vec!["loop", "loop"],
+            ]
+        );
+    }
+}
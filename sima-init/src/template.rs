@@ -0,0 +1,187 @@
+use crate::config::ServiceConfig;
+use nix::unistd::{User, getuid};
+use std::path::PathBuf;
+
+/// Template services are named `prefix@` and defined in `prefix@.yml`.
+pub fn is_template(name: &str) -> bool {
+    name.ends_with('@')
+}
+
+/// Splits an instance name such as `getty@tty2` into `("getty@", "tty2")`. The instance
+/// ends up unquoted in command lines and paths, so only names made of letters, digits
+/// and `:_.-` are accepted, and neither `.` nor `..`.
+pub fn split_instance(name: &str) -> Option<(&str, &str)> {
+    let at = name.find('@')?;
+    let (template, instance) = name.split_at(at + 1);
+    is_valid_instance(instance).then_some((template, instance))
+}
+
+fn is_valid_instance(instance: &str) -> bool {
+    !instance.is_empty()
+        && instance != "."
+        && instance != ".."
+        && instance
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, ':' | '_' | '.' | '-'))
+}
+
+/// Values substituted for the specifiers in a template.
+struct Specifiers {
+    /// `%i`: the instance name.
+    instance: String,
+    /// `%n`: the full service name.
+    name: String,
+    /// `%h`: the home directory of the user running sima-init.
+    home: String,
+}
+
+impl Specifiers {
+    fn expand(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('i') => out.push_str(&self.instance),
+                Some('n') => out.push_str(&self.name),
+                Some('h') => out.push_str(&self.home),
+                Some('%') => out.push('%'),
+                Some(other) => {
+                    out.push('%');
+                    out.push(other);
+                }
+                None => out.push('%'),
+            }
+        }
+        out
+    }
+
+    fn expand_paths(&self, paths: &[PathBuf]) -> Vec<PathBuf> {
+        paths
+            .iter()
+            .map(|path| PathBuf::from(self.expand(&path.to_string_lossy())))
+            .collect()
+    }
+}
+
+/// Builds the definition of `template` for `instance`, substituting `%i`, `%n`, `%h` and
+/// `%%` in the description, cmdline, environment and paths.
+pub fn instantiate(template: &ServiceConfig, instance: &str) -> ServiceConfig {
+    let home = User::from_uid(getuid())
+        .ok()
+        .flatten()
+        .map(|user| user.dir.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "/".to_string());
+    instantiate_with_home(template, instance, home)
+}
+
+fn instantiate_with_home(template: &ServiceConfig, instance: &str, home: String) -> ServiceConfig {
+    let specs = Specifiers {
+        instance: instance.to_string(),
+        name: format!("{}{}", template.name, instance),
+        home,
+    };
+
+    ServiceConfig {
+        name: specs.name.clone(),
+        description: template.description.as_deref().map(|d| specs.expand(d)),
+        cmdline: specs.expand(&template.cmdline),
+        environment: template
+            .environment
+            .as_ref()
+            .map(|env| env.iter().map(|entry| specs.expand(entry)).collect()),
+        read_only_paths: specs.expand_paths(&template.read_only_paths),
+        read_write_paths: specs.expand_paths(&template.read_write_paths),
+        inaccessible_paths: specs.expand_paths(&template.inaccessible_paths),
+        requires_mounts_for: specs.expand_paths(&template.requires_mounts_for),
+        conditions: template
+            .conditions
+            .iter()
+            .map(|c| c.map_value(&|v| specs.expand(v)))
+            .collect(),
+        assertions: template
+            .assertions
+            .iter()
+            .map(|c| c.map_value(&|v| specs.expand(v)))
+            .collect(),
+        bind_paths: template
+            .bind_paths
+            .iter()
+            .map(|b| specs.expand(b))
+            .collect(),
+        ..template.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instantiate_with_home, split_instance};
+    use crate::config::ServiceConfig;
+    use std::path::PathBuf;
+
+    #[test]
+    fn split_instance_requires_an_instance() {
+        assert_eq!(split_instance("getty@tty2"), Some(("getty@", "tty2")));
+        assert_eq!(split_instance("getty@"), None);
+        assert_eq!(split_instance("getty"), None);
+    }
+
+    #[test]
+    fn split_instance_rejects_unsafe_instances() {
+        assert_eq!(
+            split_instance("mount@dev:sda1_x-2.1"),
+            Some(("mount@", "dev:sda1_x-2.1"))
+        );
+        for name in [
+            "getty@x;rm -rf /",
+            "getty@$(reboot)",
+            "getty@../../etc",
+            "getty@a/b",
+            "getty@.",
+            "getty@..",
+            "getty@a b",
+            "getty@a@b",
+        ] {
+            assert_eq!(split_instance(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn instantiate_substitutes_specifiers() {
+        let template: ServiceConfig = serde_yaml::from_str(
+            r#"
+name: getty@
+description: Getty on %i
+cmdline: /sbin/agetty %i 115200 --issue-file %h/issue
+environment:
+  - 'SERVICE=%n'
+  - 'LITERAL=100%%'
+read_write_paths: ['/run/getty/%i']
+"#,
+        )
+        .expect("template should parse");
+
+        let service = instantiate_with_home(&template, "tty2", "/root".to_string());
+
+        assert_eq!(service.name, "getty@tty2");
+        assert_eq!(service.description.as_deref(), Some("Getty on tty2"));
+        assert_eq!(
+            service.cmdline,
+            "/sbin/agetty tty2 115200 --issue-file /root/issue"
+        );
+        assert_eq!(
+            service.environment,
+            Some(vec![
+                "SERVICE=getty@tty2".to_string(),
+                "LITERAL=100%".to_string()
+            ])
+        );
+        assert_eq!(
+            service.read_write_paths,
+            vec![PathBuf::from("/run/getty/tty2")]
+        );
+    }
+}
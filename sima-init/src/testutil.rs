@@ -0,0 +1,22 @@
+//! Helpers shared by the unit tests.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An empty directory under the system temporary directory, unique to this test run.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sima-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("scratch dir should be created");
+    dir
+}
+
+/// Writes a minimal service file named `file` to `dir`.
+pub fn write_service(dir: &Path, file: &str, name: &str, cmdline: &str) {
+    fs::create_dir_all(dir).expect("service dir should be created");
+    fs::write(
+        dir.join(file),
+        format!("name: {name}\ncmdline: {cmdline}\n"),
+    )
+    .expect("service file should be written");
+}
@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::fs::{self, DirBuilder};
 use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
 
 pub const PRIMARY_SOCKET_PATH: &str = "/run/sima.sock";
 pub const FALLBACK_SOCKET_PATH: &str = "/tmp/sima.sock";
@@ -8,6 +11,54 @@ pub fn socket_paths() -> [&'static str; 2] {
     [PRIMARY_SOCKET_PATH, FALLBACK_SOCKET_PATH]
 }
 
+/// Socket of a supervisor run by user `uid`: `$XDG_RUNTIME_DIR/sima.sock`, or
+/// `sima.sock` in [`user_fallback_dir`] without a runtime directory.
+pub fn user_socket_path(uid: u32) -> String {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => format!("{dir}/sima.sock"),
+        _ => format!("{}/sima.sock", user_fallback_dir(uid).display()),
+    }
+}
+
+/// Directory of user `uid` under /tmp for what would otherwise go to
+/// `$XDG_RUNTIME_DIR` or the home directory. Anyone can create it first, so it is only
+/// used after [`ensure_private_dir`].
+pub fn user_fallback_dir(uid: u32) -> PathBuf {
+    PathBuf::from(format!("/tmp/sima-{uid}"))
+}
+
+/// Creates `dir` with mode 0700 if it is missing, and checks that it is a directory,
+/// not a symlink, owned by `uid` and not accessible to anyone else.
+pub fn ensure_private_dir(dir: &Path, uid: u32) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::other(format!(
+            "{} is not a directory",
+            dir.display()
+        )));
+    }
+    if metadata.uid() != uid {
+        return Err(io::Error::other(format!(
+            "{} is owned by uid {}, not {uid}",
+            dir.display(),
+            metadata.uid()
+        )));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::other(format!(
+            "{} is accessible to other users (mode {:o})",
+            dir.display(),
+            metadata.mode() & 0o777
+        )));
+    }
+    Ok(())
+}
+
 pub fn should_fallback_from_socket_error(err: &io::Error) -> bool {
     matches!(
         err.kind(),
@@ -31,15 +82,39 @@ pub enum Request {
     Restart(String),
     Status,
     Poweroff,
-    Reboot,
+    /// Reboot; `arg` is passed to the firmware or bootloader, e.g. `bootloader`.
+    Reboot {
+        arg: Option<String>,
+    },
     SoftReboot,
+    /// Stop the system without powering it off.
+    Halt,
+    /// Reboot straight into another kernel.
+    Kexec(KexecImage),
+    /// Shut down later, replacing any shutdown already scheduled.
+    ScheduleShutdown(ScheduledShutdown),
+    CancelShutdown,
+    ReloadConfig,
+    /// Execute sima-init again, e.g. after an upgrade, keeping the services running.
+    DaemonReexec,
+    /// Show the merged definition of a service.
+    Cat(String),
+    Enable(String),
+    Disable(String),
+    Mask(String),
+    Unmask(String),
+    /// Start the services of a target and stop all others.
+    Isolate(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     Ok,
     Error(String),
-    StatusReport(Vec<ServiceInfo>),
+    /// The services, and the shutdown scheduled, if any.
+    StatusReport(Vec<ServiceInfo>, Option<ScheduledShutdown>),
+    ConfigReloaded(ConfigDiff),
+    Definition(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,6 +122,68 @@ pub struct ServiceInfo {
     pub name: String,
     pub pid: Option<i32>,
     pub running: bool,
+    /// The definition changed while the service was running.
+    pub needs_restart: bool,
+    pub enablement: Enablement,
+    /// The start condition that was not met, if the service was skipped.
+    pub skipped: Option<String>,
+    /// Why the last start failed.
+    pub failed: Option<String>,
+    /// An /etc/fstab entry rather than a service; `running` means mounted.
+    pub mount: bool,
+}
+
+/// The kernel `simactl kexec` boots. Without a kernel, the one already loaded with
+/// `kexec -l` is used.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KexecImage {
+    pub kernel: Option<PathBuf>,
+    pub initrd: Option<PathBuf>,
+    /// Defaults to the command line of the running kernel.
+    pub cmdline: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShutdownKind {
+    Poweroff,
+    Reboot,
+    Halt,
+}
+
+impl ShutdownKind {
+    /// What the system is about to do, as in "the system will power off".
+    pub fn verb(self) -> &'static str {
+        match self {
+            ShutdownKind::Poweroff => "power off",
+            ShutdownKind::Reboot => "reboot",
+            ShutdownKind::Halt => "halt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledShutdown {
+    pub kind: ShutdownKind,
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+}
+
+/// Whether a service is started at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Enablement {
+    Enabled,
+    Disabled,
+    /// Refuses to start, even on request.
+    Masked,
+}
+
+/// Service names affected by a configuration reload.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    /// Removed services that are still running are dropped once they stop.
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
 }
 
 pub fn encode<T: Serialize>(msg: &T) -> Result<Vec<u8>, postcard::Error> {
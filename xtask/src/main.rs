@@ -32,7 +32,7 @@ fn run_init() -> Result<()> {
          mkdir -p /etc/sima.d && \
          cp {sima_yml} /etc/sima.yml && \
          cp -r {sima_d}/* /etc/sima.d/ && \
-         exec {bin}",
+         exec {bin} --state-dir /run/sima",
         sima_yml = tests_dir.join("sima.yml").display(),
         sima_d = tests_dir.join("sima.d").display(),
         bin = binary_path.display()